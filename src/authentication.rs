@@ -0,0 +1,94 @@
+use std::sync::Arc;
+
+use axum::{
+    extract::{Request, State},
+    http::{header, HeaderValue},
+    middleware::Next,
+    response::{IntoResponse, Response},
+};
+use secrecy::{ExposeSecret, Secret};
+use subtle::ConstantTimeEq;
+
+use crate::AppError;
+
+/// The bearer token admins present to reach `/admin` and to publish newsletters.
+pub struct AdminToken(Secret<String>);
+
+impl AdminToken {
+    pub fn new(token: Secret<String>) -> Self {
+        Self(token)
+    }
+
+    /// Compares in constant time so the token cannot be guessed byte by byte.
+    fn accepts(&self, presented: &str) -> bool {
+        self.0
+            .expose_secret()
+            .as_bytes()
+            .ct_eq(presented.as_bytes())
+            .into()
+    }
+}
+
+/// Answers 401 unless the request carries `Authorization: Bearer <application.admin_token>`.
+pub async fn require_admin(
+    State(token): State<Arc<AdminToken>>,
+    request: Request,
+    next: Next,
+) -> Response {
+    let authorized = request
+        .headers()
+        .get(header::AUTHORIZATION)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.strip_prefix("Bearer "))
+        .is_some_and(|presented| token.accepts(presented));
+    if authorized {
+        return next.run(request).await;
+    }
+    tracing::warn!("Rejected an unauthenticated admin request");
+    let mut response = AppError::Unauthorized.into_response();
+    response
+        .headers_mut()
+        .insert(header::WWW_AUTHENTICATE, HeaderValue::from_static("Bearer"));
+    response
+}
+
+#[cfg(test)]
+mod tests {
+    use axum::{body::Body, http::StatusCode, middleware, routing::get, Router};
+    use tower::ServiceExt;
+
+    use super::*;
+
+    fn router() -> Router {
+        let token = Arc::new(AdminToken::new(Secret::new("admin-token".into())));
+        Router::new()
+            .route("/", get(|| async { "secret" }))
+            .layer(middleware::from_fn_with_state(token, require_admin))
+    }
+
+    async fn status_with(authorization: Option<&str>) -> StatusCode {
+        let mut request = Request::builder().uri("/");
+        if let Some(value) = authorization {
+            request = request.header(header::AUTHORIZATION, value);
+        }
+        let request = request.body(Body::empty()).unwrap();
+        router().oneshot(request).await.unwrap().status()
+    }
+
+    #[tokio::test]
+    async fn only_the_configured_bearer_token_is_let_through() {
+        assert_eq!(
+            status_with(Some("Bearer admin-token")).await,
+            StatusCode::OK
+        );
+        assert_eq!(status_with(None).await, StatusCode::UNAUTHORIZED);
+        assert_eq!(
+            status_with(Some("Bearer admin-tokens")).await,
+            StatusCode::UNAUTHORIZED
+        );
+        assert_eq!(
+            status_with(Some("Basic admin-token")).await,
+            StatusCode::UNAUTHORIZED
+        );
+    }
+}
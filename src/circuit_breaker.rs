@@ -0,0 +1,168 @@
+use std::{sync::Mutex, time::Duration};
+
+use serde::Serialize;
+use tokio::time::Instant;
+
+use crate::configuration::CircuitBreakerSettings;
+
+#[derive(Serialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum CircuitState {
+    /// Requests flow normally.
+    Closed,
+    /// Requests are rejected without reaching the provider.
+    Open,
+    /// A single trial request is allowed through to probe the provider.
+    HalfOpen,
+}
+/// Stops calling a provider after repeated failures and probes it again after a cooldown.
+#[derive(Debug)]
+pub struct CircuitBreaker {
+    name: &'static str,
+    failure_threshold: u32,
+    cooldown: Duration,
+    inner: Mutex<Inner>,
+}
+#[derive(Debug)]
+struct Inner {
+    state: CircuitState,
+    consecutive_failures: u32,
+    /// When the circuit opened, or when the half-open trial started.
+    since: Instant,
+}
+impl CircuitBreaker {
+    pub fn new(name: &'static str, settings: &CircuitBreakerSettings) -> Self {
+        Self {
+            name,
+            failure_threshold: settings.failure_threshold.max(1),
+            cooldown: Duration::from_secs(settings.cooldown_secs),
+            inner: Mutex::new(Inner {
+                state: CircuitState::Closed,
+                consecutive_failures: 0,
+                since: Instant::now(),
+            }),
+        }
+    }
+    pub fn state(&self) -> CircuitState {
+        self.inner
+            .lock()
+            .expect("circuit breaker lock poisoned")
+            .state
+    }
+    /// Returns whether a request may be sent right now.
+    pub fn try_acquire(&self) -> bool {
+        let mut inner = self.inner.lock().expect("circuit breaker lock poisoned");
+        match inner.state {
+            CircuitState::Closed => true,
+            // A trial that never reported back must not keep the circuit half-open forever.
+            CircuitState::Open | CircuitState::HalfOpen
+                if inner.since.elapsed() >= self.cooldown =>
+            {
+                self.transition(&mut inner, CircuitState::HalfOpen);
+                true
+            }
+            CircuitState::Open | CircuitState::HalfOpen => false,
+        }
+    }
+    pub fn record_success(&self) {
+        let mut inner = self.inner.lock().expect("circuit breaker lock poisoned");
+        inner.consecutive_failures = 0;
+        if inner.state != CircuitState::Closed {
+            self.transition(&mut inner, CircuitState::Closed);
+        }
+    }
+    pub fn record_failure(&self) {
+        let mut inner = self.inner.lock().expect("circuit breaker lock poisoned");
+        inner.consecutive_failures += 1;
+        let should_open = match inner.state {
+            CircuitState::Closed => inner.consecutive_failures >= self.failure_threshold,
+            CircuitState::HalfOpen => true,
+            CircuitState::Open => false,
+        };
+        if should_open {
+            self.transition(&mut inner, CircuitState::Open);
+        }
+    }
+    fn transition(&self, inner: &mut Inner, state: CircuitState) {
+        match state {
+            CircuitState::Open => tracing::warn!(
+                provider = self.name,
+                failures = inner.consecutive_failures,
+                "Email provider circuit opened"
+            ),
+            CircuitState::HalfOpen => {
+                tracing::info!(provider = self.name, "Email provider circuit half-open")
+            }
+            CircuitState::Closed => {
+                tracing::info!(provider = self.name, "Email provider circuit closed")
+            }
+        }
+        inner.state = state;
+        inner.since = Instant::now();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use crate::{
+        circuit_breaker::{CircuitBreaker, CircuitState},
+        configuration::CircuitBreakerSettings,
+    };
+
+    fn breaker() -> CircuitBreaker {
+        CircuitBreaker::new(
+            "primary",
+            &CircuitBreakerSettings {
+                failure_threshold: 3,
+                cooldown_secs: 30,
+            },
+        )
+    }
+    #[tokio::test(start_paused = true)]
+    async fn opens_after_repeated_failures() {
+        let breaker = breaker();
+        for _ in 0..2 {
+            breaker.record_failure();
+        }
+        assert_eq!(breaker.state(), CircuitState::Closed);
+        breaker.record_failure();
+        assert_eq!(breaker.state(), CircuitState::Open);
+        assert!(!breaker.try_acquire());
+    }
+    #[tokio::test(start_paused = true)]
+    async fn success_resets_the_failure_count() {
+        let breaker = breaker();
+        breaker.record_failure();
+        breaker.record_failure();
+        breaker.record_success();
+        breaker.record_failure();
+        assert_eq!(breaker.state(), CircuitState::Closed);
+    }
+    #[tokio::test(start_paused = true)]
+    async fn allows_a_single_trial_after_the_cooldown() {
+        let breaker = breaker();
+        for _ in 0..3 {
+            breaker.record_failure();
+        }
+        tokio::time::advance(Duration::from_secs(30)).await;
+        assert!(breaker.try_acquire());
+        assert_eq!(breaker.state(), CircuitState::HalfOpen);
+        assert!(!breaker.try_acquire());
+        breaker.record_success();
+        assert_eq!(breaker.state(), CircuitState::Closed);
+    }
+    #[tokio::test(start_paused = true)]
+    async fn failed_trial_reopens_the_circuit() {
+        let breaker = breaker();
+        for _ in 0..3 {
+            breaker.record_failure();
+        }
+        tokio::time::advance(Duration::from_secs(30)).await;
+        assert!(breaker.try_acquire());
+        breaker.record_failure();
+        assert_eq!(breaker.state(), CircuitState::Open);
+        assert!(!breaker.try_acquire());
+    }
+}
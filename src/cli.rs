@@ -0,0 +1,420 @@
+use std::{
+    borrow::Cow,
+    io::{self, Write},
+    path::PathBuf,
+    time::Duration,
+};
+
+use clap::{Args, Parser, Subcommand};
+use tracing_subscriber::fmt::writer::BoxMakeWriter;
+
+use crate::{
+    configuration::{get_configuration_from, Environment, RunMode, Settings, DEFAULT_CONFIG_DIR},
+    seed::SeedOptions,
+    startup::{serve, ApplicationBaseUrl},
+    subscriber::FormData,
+    telemetry::{get_subscriber, init_subscriber, init_tracer, tracer},
+    workers::{try_execute_task, ExecutionOutcome},
+    AppError, EmailClient, Result, Storage,
+};
+
+/// How long `healthcheck` waits for the server to answer.
+const HEALTHCHECK_TIMEOUT: Duration = Duration::from_secs(5);
+
+#[derive(Parser, Debug)]
+#[command(version, about)]
+pub struct Cli {
+    /// Directory holding `base.toml` and the per-environment configuration files.
+    #[arg(
+        long = "config",
+        env = "APP_CONFIG_DIR",
+        default_value = DEFAULT_CONFIG_DIR,
+        global = true
+    )]
+    pub config_dir: PathBuf,
+    /// What to do; starts the server when omitted.
+    #[command(subcommand)]
+    pub command: Option<Command>,
+}
+#[derive(Subcommand, Debug, PartialEq)]
+pub enum Command {
+    /// Run the API server, the scheduler and delivery workers, or both.
+    Serve {
+        /// Overrides `application.mode`.
+        #[arg(long, value_enum)]
+        mode: Option<RunMode>,
+    },
+    /// Apply the database schema.
+    Migrate,
+    /// Manage subscribers.
+    #[command(subcommand)]
+    Subscribers(SubscribersCommand),
+    /// Manage newsletter issues.
+    #[command(subcommand)]
+    Issues(IssuesCommand),
+    /// Fill the database with reproducible demo data; refused in production and whenever
+    /// `email_client` points at a real provider.
+    Seed(SeedArgs),
+    /// Inspect the configuration.
+    #[command(subcommand)]
+    Config(ConfigCommand),
+    /// Exit successfully only if the running server reports itself ready.
+    Healthcheck {
+        /// Endpoint to query; defaults to `/health/ready` on the application port, or to
+        /// `/metrics` on the metrics port when `application.mode` is `worker`.
+        #[arg(long)]
+        url: Option<String>,
+    },
+}
+#[derive(Subcommand, Debug, PartialEq)]
+pub enum SubscribersCommand {
+    /// Subscribe an address, confirmed straight away.
+    Add(SubscriberArgs),
+    /// Unsubscribe an address.
+    Remove {
+        #[arg(long)]
+        email: String,
+    },
+    /// Print every subscriber as a table.
+    List,
+    /// Write every subscriber as CSV.
+    Export {
+        /// File to write to; defaults to standard output.
+        #[arg(long, short)]
+        output: Option<PathBuf>,
+    },
+}
+#[derive(Args, Debug, PartialEq)]
+pub struct SubscriberArgs {
+    #[arg(long)]
+    pub email: String,
+    #[arg(long)]
+    pub name: String,
+}
+#[derive(Subcommand, Debug, PartialEq)]
+pub enum IssuesCommand {
+    /// Send an issue to every subscriber now.
+    Send {
+        /// Id of a draft or scheduled issue.
+        id: String,
+        /// Only enqueue the deliveries and leave sending to the running workers.
+        #[arg(long)]
+        no_wait: bool,
+    },
+}
+#[derive(Args, Debug, PartialEq)]
+pub struct SeedArgs {
+    /// How many subscribers to create.
+    #[arg(long, default_value_t = 100)]
+    pub subscribers: usize,
+    /// How many issues to create, in mixed states.
+    #[arg(long, default_value_t = 10)]
+    pub issues: usize,
+    /// Seed for the random generator; the same seed gives the same data.
+    #[arg(long, default_value_t = 42)]
+    pub seed: u64,
+    /// Namespace to fill instead of the configured one.
+    #[arg(long)]
+    pub namespace: Option<String>,
+}
+#[derive(Subcommand, Debug, PartialEq)]
+pub enum ConfigCommand {
+    /// Load and validate the configuration without starting anything.
+    Check,
+}
+
+impl Cli {
+    /// Loads the configuration, sets up telemetry and runs the selected command.
+    ///
+    /// Only `serve` logs to standard output; every other command logs to standard error so
+    /// its output can be piped.
+    pub async fn run(self) -> Result<()> {
+        let command = self.command.unwrap_or(Command::Serve { mode: None });
+        let configuration = get_configuration_from(&self.config_dir)?;
+        crate::redaction::set_log_pii(configuration.telemetry.log_pii);
+        let tracer_provider = init_tracer("zero2prod", &configuration.telemetry)?;
+        let sink = match command {
+            Command::Serve { .. } => BoxMakeWriter::new(io::stdout),
+            _ => BoxMakeWriter::new(io::stderr),
+        };
+        let subscriber = get_subscriber(
+            "zero2prod",
+            &configuration.logging,
+            sink,
+            tracer(&tracer_provider, "zero2prod"),
+        )?;
+        init_subscriber(subscriber);
+        let result = match command {
+            Command::Serve { mode } => {
+                let mut configuration = configuration;
+                if let Some(mode) = mode {
+                    configuration.application.mode = mode;
+                }
+                serve(configuration).await
+            }
+            Command::Migrate => migrate(&configuration).await,
+            Command::Subscribers(command) => subscribers(&configuration, command).await,
+            Command::Issues(IssuesCommand::Send { id, no_wait }) => {
+                send_issue(&configuration, &id, no_wait).await
+            }
+            Command::Seed(args) => seed(configuration, args).await,
+            Command::Config(ConfigCommand::Check) => check_config(&configuration),
+            Command::Healthcheck { url } => healthcheck(&configuration, url).await,
+        };
+        // Flushing blocks on the exporter's HTTP client, so keep it off the async workers.
+        tokio::task::spawn_blocking(move || tracer_provider.shutdown()).await??;
+        result
+    }
+}
+
+async fn migrate(configuration: &Settings) -> Result<()> {
+    let storage = Storage::init(configuration.database.clone()).await?;
+    let statements = storage.migrate().await?;
+    println!("Applied {statements} schema statements");
+    Ok(())
+}
+
+async fn subscribers(configuration: &Settings, command: SubscribersCommand) -> Result<()> {
+    let storage = Storage::init(configuration.database.clone()).await?;
+    match command {
+        SubscribersCommand::Add(args) => {
+            let subscriber = storage
+                .add_subscriber(FormData {
+                    email: args.email,
+                    name: args.name,
+                })
+                .await?;
+            // The operator vouches for the address, so no confirmation email is sent.
+            storage.confirm_subscriber(&subscriber.id).await?;
+            println!("Added subscriber {}", subscriber.id.id.to_raw());
+        }
+        SubscribersCommand::Remove { email } => {
+            let subscriber = storage.get_subscriber_by_email(&email).await?;
+            let id = subscriber.id.id.to_raw();
+            storage.delete_subscriber(subscriber).await?;
+            println!("Removed subscriber {id}");
+        }
+        SubscribersCommand::List => {
+            let mut out = io::stdout().lock();
+            for s in storage.get_subscribers().await? {
+                writeln!(
+                    out,
+                    "{}\t{}\t{}\t{}\t{}",
+                    s.id.id.to_raw(),
+                    s.email,
+                    s.username,
+                    s.subscribed_at,
+                    s.status.as_str()
+                )?;
+            }
+        }
+        SubscribersCommand::Export { output } => {
+            let subscribers = storage.get_subscribers().await?;
+            let out: Box<dyn Write> = match output {
+                Some(path) => Box::new(std::fs::File::create(path)?),
+                None => Box::new(io::stdout().lock()),
+            };
+            let mut out = io::BufWriter::new(out);
+            writeln!(out, "id,email,name,subscribed_at,status")?;
+            for s in subscribers {
+                writeln!(
+                    out,
+                    "{},{},{},{},{}",
+                    csv_field(&s.id.id.to_raw()),
+                    csv_field(&s.email),
+                    csv_field(&s.username),
+                    csv_field(&s.subscribed_at.to_raw()),
+                    s.status.as_str()
+                )?;
+            }
+            out.flush()?;
+        }
+    }
+    Ok(())
+}
+
+/// Marks the issue as due, enqueues a delivery per subscriber and, unless `no_wait` is set,
+/// sends them from this process.
+async fn send_issue(configuration: &Settings, id: &str, no_wait: bool) -> Result<()> {
+    let storage = Storage::init(configuration.database.clone()).await?;
+    let mut issue = storage.get_issue(id).await?;
+    let current = issue.status;
+    issue.send_now()?;
+    let issue = storage.update_issue(issue, current).await?;
+    storage.enqueue_issue(&issue.id).await?;
+    storage.complete_issue_if_delivered(&issue.id).await?;
+    let counts = storage.get_delivery_counts(&issue.id).await?;
+    println!("Enqueued {} deliveries", counts.queued);
+    if no_wait {
+        return Ok(());
+    }
+    let email_client =
+        EmailClient::with_shared_rate_limit(&configuration.email_client, storage.clone())?;
+    let base_url = ApplicationBaseUrl::new(
+        configuration.application.base_url.clone(),
+        configuration.application.hmac_secret.clone(),
+    );
+    // The queue is shared, so this also drains deliveries that other issues left behind.
+    while let ExecutionOutcome::TaskCompleted =
+        try_execute_task(&storage, &email_client, &base_url).await?
+    {}
+    let counts = storage.get_delivery_counts(&issue.id).await?;
+    println!(
+        "Sent {}, failed {}, bounced {}, suppressed {}",
+        counts.sent, counts.failed, counts.bounced, counts.suppressed
+    );
+    Ok(())
+}
+
+async fn seed(mut configuration: Settings, args: SeedArgs) -> Result<()> {
+    if matches!(Environment::current()?, Environment::Production) {
+        return Err(AppError::Custom(anyhow::anyhow!(
+            "seeding is disabled in the production environment"
+        )));
+    }
+    if !crate::seed::mail_stays_local(&configuration.email_client) {
+        return Err(AppError::Custom(anyhow::anyhow!(
+            "seeding is disabled while email_client points at a real provider ({}): a delivery \
+             worker would mail the generated subscribers; point it at a local mock first",
+            configuration.email_client.base_url
+        )));
+    }
+    if let Some(namespace) = args.namespace {
+        configuration.database.namespace = namespace;
+    }
+    let storage = Storage::init(configuration.database.clone()).await?;
+    let data = crate::seed::generate(
+        &SeedOptions {
+            subscribers: args.subscribers,
+            issues: args.issues,
+            seed: args.seed,
+        },
+        chrono::Utc::now(),
+    );
+    crate::seed::seed(&storage, &data).await?;
+    println!(
+        "Seeded {} subscribers, {} issues and {} deliveries into {}/{}",
+        data.subscribers.len(),
+        data.issues.len(),
+        data.deliveries.len(),
+        configuration.database.namespace,
+        configuration.database.database_name
+    );
+    Ok(())
+}
+
+fn check_config(configuration: &Settings) -> Result<()> {
+    println!(
+        "Configuration is valid: serving on {}, metrics on {}, database at {}:{}",
+        configuration.app_addr(),
+        configuration.metrics_addr(),
+        configuration.database.host,
+        configuration.database.port
+    );
+    Ok(())
+}
+
+async fn healthcheck(configuration: &Settings, url: Option<String>) -> Result<()> {
+    let application = &configuration.application;
+    let url = url.unwrap_or_else(|| match application.mode {
+        RunMode::Worker => format!("http://127.0.0.1:{}/metrics", application.metrics_port),
+        _ => format!("http://127.0.0.1:{}/health/ready", application.port),
+    });
+    let response = reqwest::Client::builder()
+        .timeout(HEALTHCHECK_TIMEOUT)
+        .build()?
+        .get(&url)
+        .send()
+        .await?;
+    let status = response.status();
+    if !status.is_success() {
+        let body = response.text().await.unwrap_or_default();
+        return Err(AppError::Custom(anyhow::anyhow!(
+            "{url} answered {status}: {body}"
+        )));
+    }
+    println!("{url} answered {status}");
+    Ok(())
+}
+
+/// Quotes a CSV field when it contains a separator, a quote or a line break.
+fn csv_field(value: &str) -> Cow<'_, str> {
+    if value.contains([',', '"', '\n', '\r']) {
+        Cow::Owned(format!("\"{}\"", value.replace('"', "\"\"")))
+    } else {
+        Cow::Borrowed(value)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn no_subcommand_means_serve() {
+        let cli = Cli::try_parse_from(["zero2prod"]).unwrap();
+        assert_eq!(cli.command, None);
+        assert_eq!(cli.config_dir, PathBuf::from(DEFAULT_CONFIG_DIR));
+    }
+
+    #[test]
+    fn serve_takes_a_run_mode() {
+        let cli = Cli::try_parse_from(["zero2prod", "serve", "--mode", "worker"]).unwrap();
+        assert_eq!(
+            cli.command,
+            Some(Command::Serve {
+                mode: Some(RunMode::Worker)
+            })
+        );
+        assert!(Cli::try_parse_from(["zero2prod", "serve", "--mode", "cron"]).is_err());
+    }
+
+    #[test]
+    fn config_flag_is_accepted_after_the_subcommand() {
+        let cli =
+            Cli::try_parse_from(["zero2prod", "issues", "send", "abc", "--config", "/etc/app"])
+                .unwrap();
+        assert_eq!(cli.config_dir, PathBuf::from("/etc/app"));
+        assert_eq!(
+            cli.command,
+            Some(Command::Issues(IssuesCommand::Send {
+                id: "abc".into(),
+                no_wait: false
+            }))
+        );
+    }
+
+    #[test]
+    fn subscribers_add_requires_email_and_name() {
+        assert!(
+            Cli::try_parse_from(["zero2prod", "subscribers", "add", "--email", "a@b.c"]).is_err()
+        );
+        let cli = Cli::try_parse_from([
+            "zero2prod",
+            "subscribers",
+            "add",
+            "--email",
+            "a@b.c",
+            "--name",
+            "Ursula",
+        ])
+        .unwrap();
+        assert_eq!(
+            cli.command,
+            Some(Command::Subscribers(SubscribersCommand::Add(
+                SubscriberArgs {
+                    email: "a@b.c".into(),
+                    name: "Ursula".into()
+                }
+            )))
+        );
+    }
+
+    #[test]
+    fn csv_fields_are_quoted_only_when_needed() {
+        assert_eq!(csv_field("plain"), "plain");
+        assert_eq!(csv_field("Le Guin, Ursula"), "\"Le Guin, Ursula\"");
+        assert_eq!(csv_field("say \"hi\""), "\"say \"\"hi\"\"\"");
+        assert_eq!(csv_field("two\nlines"), "\"two\nlines\"");
+    }
+}
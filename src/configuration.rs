@@ -1,9 +1,11 @@
-use config::Config;
+use std::{collections::HashMap, path::Path, time::Duration};
+
+use config::{Config, Source};
 use secrecy::{ExposeSecret, Secret};
 use serde::Deserialize;
 use surrealdb::opt::auth::Root;
 
-use crate::AppError;
+use crate::{validator::validate_email, AppError};
 
 /// Represents the application settings.
 #[derive(Deserialize, Debug)]
@@ -14,17 +16,221 @@ pub struct Settings {
     pub application: ApplicationSettings,
     /// The email client settings.
     pub email_client: EmailClientSettings,
+    /// The tracing settings.
+    #[serde(default)]
+    pub telemetry: TelemetrySettings,
+    /// The log output settings.
+    #[serde(default)]
+    pub logging: LoggingSettings,
+    /// The subscription confirmation settings.
+    #[serde(default)]
+    pub subscriptions: SubscriptionSettings,
+}
+/// Represents how long unconfirmed subscriptions are kept around.
+#[derive(Deserialize, Debug, Clone)]
+#[serde(default)]
+pub struct SubscriptionSettings {
+    /// How long a confirmation link stays valid, in hours.
+    pub confirmation_ttl_hours: u64,
+    /// How long a subscriber may stay unconfirmed before being removed, in days.
+    pub unconfirmed_retention_days: u64,
+    /// How often expired links and stale unconfirmed subscribers are removed, in seconds.
+    pub cleanup_interval_secs: u64,
+    /// How long, in minutes, before a repeated subscription request mails the same address
+    /// again; requests in between are answered but send nothing.
+    pub resend_cooldown_minutes: u64,
+}
+impl Default for SubscriptionSettings {
+    fn default() -> Self {
+        Self {
+            confirmation_ttl_hours: 48,
+            unconfirmed_retention_days: 7,
+            cleanup_interval_secs: 3600,
+            resend_cooldown_minutes: 10,
+        }
+    }
+}
+impl SubscriptionSettings {
+    pub fn confirmation_ttl(&self) -> Duration {
+        Duration::from_secs(self.confirmation_ttl_hours * 3600)
+    }
+    pub fn unconfirmed_retention(&self) -> Duration {
+        Duration::from_secs(self.unconfirmed_retention_days * 24 * 3600)
+    }
+    pub fn cleanup_interval(&self) -> Duration {
+        Duration::from_secs(self.cleanup_interval_secs)
+    }
+    pub fn resend_cooldown(&self) -> Duration {
+        Duration::from_secs(self.resend_cooldown_minutes * 60)
+    }
+}
+/// Represents how and where logs are written.
+#[derive(Deserialize, Debug, Clone)]
+#[serde(default)]
+pub struct LoggingSettings {
+    /// The output format.
+    pub format: LogFormat,
+    /// The default level, e.g. `info`.
+    pub level: String,
+    /// Extra filter directives such as `surrealdb=warn`, applied on top of `level`.
+    /// `RUST_LOG` replaces both when it is set.
+    pub directives: Vec<String>,
+    /// Also write logs to a rotating file.
+    pub file: Option<LogFileSettings>,
+}
+impl Default for LoggingSettings {
+    fn default() -> Self {
+        Self {
+            format: LogFormat::default(),
+            level: "info".into(),
+            directives: Vec::new(),
+            file: None,
+        }
+    }
+}
+impl LoggingSettings {
+    /// The filter directives, starting with the default level.
+    pub fn filter(&self) -> String {
+        std::iter::once(self.level.as_str())
+            .chain(self.directives.iter().map(String::as_str))
+            .collect::<Vec<_>>()
+            .join(",")
+    }
+}
+/// Represents the shape of each log line.
+#[derive(Deserialize, Debug, Clone, Copy, Default, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum LogFormat {
+    /// Bunyan-compatible JSON, for log aggregators.
+    #[default]
+    Bunyan,
+    /// Multi-line, human-readable output.
+    Pretty,
+    /// Single-line, human-readable output.
+    Compact,
+}
+/// Represents the rotating log file.
+#[derive(Deserialize, Debug, Clone)]
+pub struct LogFileSettings {
+    /// The path of the current log file. Rotated files get a `.1`, `.2`, ... suffix.
+    pub path: std::path::PathBuf,
+    /// When the file is rotated.
+    #[serde(default)]
+    pub rotation: LogRotation,
+    /// The size in megabytes after which a file is rotated when `rotation` is `size`.
+    #[serde(default = "default_max_size_mb")]
+    pub max_size_mb: u64,
+    /// How many rotated files are kept.
+    #[serde(default = "default_max_files")]
+    pub max_files: usize,
+}
+fn default_max_size_mb() -> u64 {
+    100
+}
+fn default_max_files() -> usize {
+    7
+}
+/// Represents when the log file is rotated.
+#[derive(Deserialize, Debug, Clone, Copy, Default, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum LogRotation {
+    #[default]
+    Daily,
+    Hourly,
+    /// Once the file grows past `max_size_mb`.
+    Size,
+}
+/// Represents where spans are exported to.
+#[derive(Deserialize, Debug, Clone, Default)]
+pub struct TelemetrySettings {
+    /// The OTLP/HTTP traces endpoint, e.g. `http://localhost:4318/v1/traces`.
+    /// Spans are not exported when it is unset.
+    #[serde(default)]
+    pub otlp_endpoint: Option<String>,
+    /// Log emails and names unmasked. Only accepted in the local environment.
+    #[serde(default)]
+    pub log_pii: bool,
 }
 /// Represents the application settings.
 #[derive(Deserialize, Debug)]
 pub struct ApplicationSettings {
     /// The port number for the application.
     pub port: u16,
+    /// The port number the Prometheus metrics are served on.
+    pub metrics_port: u16,
     /// The host name for the application.
     pub host: String,
+    /// The public URL the application is reachable at, used to build links in emails.
+    pub base_url: String,
+    /// The key signing unsubscribe links. Keep it out of the repository.
+    pub hmac_secret: Secret<String>,
+    /// The bearer token required by `/admin` and newsletter publishing. Keep it out of the
+    /// repository.
+    pub admin_token: Secret<String>,
+    /// How long a request may take before it is answered with 408.
+    #[serde(default)]
+    pub timeouts: RequestTimeoutSettings,
+    /// Which halves of the application this process runs.
+    #[serde(default)]
+    pub mode: RunMode,
+    /// How long shutdown waits for work in flight.
+    #[serde(default)]
+    pub shutdown: ShutdownSettings,
+}
+/// Represents how long a graceful shutdown may take.
+#[derive(Deserialize, Debug, Clone)]
+#[serde(default)]
+pub struct ShutdownSettings {
+    /// How long in-flight requests and email sends get to finish after a shutdown signal,
+    /// in milliseconds. Keep it below the orchestrator's kill timeout.
+    pub drain_timeout_ms: u64,
+}
+impl Default for ShutdownSettings {
+    fn default() -> Self {
+        Self {
+            drain_timeout_ms: 20_000,
+        }
+    }
+}
+/// Represents which halves of the application a process runs, so they can be scaled apart.
+#[derive(Deserialize, Debug, Clone, Copy, Default, PartialEq, Eq, clap::ValueEnum)]
+#[serde(rename_all = "lowercase")]
+pub enum RunMode {
+    /// Only the HTTP API.
+    Api,
+    /// Only the scheduler and the delivery worker.
+    Worker,
+    /// The HTTP API and the workers in one process.
+    #[default]
+    Both,
+}
+impl RunMode {
+    pub fn runs_api(self) -> bool {
+        matches!(self, RunMode::Api | RunMode::Both)
+    }
+    pub fn runs_workers(self) -> bool {
+        matches!(self, RunMode::Worker | RunMode::Both)
+    }
+}
+/// Represents the time limits on incoming requests.
+#[derive(Deserialize, Debug, Clone)]
+#[serde(default)]
+pub struct RequestTimeoutSettings {
+    /// The limit for every route without an override, in milliseconds.
+    pub request_ms: u64,
+    /// Per-route limits in milliseconds, keyed by route pattern such as `/admin/issues/:id`.
+    pub routes: HashMap<String, u64>,
+}
+impl Default for RequestTimeoutSettings {
+    fn default() -> Self {
+        Self {
+            request_ms: 10_000,
+            routes: HashMap::new(),
+        }
+    }
 }
 /// Represents the database settings.
-#[derive(Deserialize, Debug)]
+#[derive(Deserialize, Debug, Clone)]
 pub struct DatabaseSettings {
     /// The username for the database.
     pub username: String,
@@ -38,6 +244,46 @@ pub struct DatabaseSettings {
     pub database_name: String,
     /// The namespace for the database.
     pub namespace: String,
+    /// How the connection is established and kept alive.
+    #[serde(default)]
+    pub connection: DatabaseConnectionSettings,
+}
+impl DatabaseSettings {
+    pub fn connection_string(&self) -> Secret<String> {
+        Secret::new(format!("{}:{}", self.host, self.port))
+    }
+    pub fn root(&self) -> Root<'_> {
+        Root {
+            username: &self.username,
+            password: self.password.expose_secret(),
+        }
+    }
+}
+/// Represents the retry policy for the database connection, in milliseconds.
+#[derive(Deserialize, Debug, Clone)]
+#[serde(default)]
+pub struct DatabaseConnectionSettings {
+    /// The limit for a single connection attempt, signin included.
+    pub connect_timeout_ms: u64,
+    /// How many times to try connecting at startup before giving up.
+    pub startup_attempts: u32,
+    /// The delay after the first failed attempt, doubled after each further failure.
+    pub initial_backoff_ms: u64,
+    /// The longest delay between two attempts.
+    pub max_backoff_ms: u64,
+    /// How often the connection is checked, and re-established when it is broken.
+    pub health_check_interval_ms: u64,
+}
+impl Default for DatabaseConnectionSettings {
+    fn default() -> Self {
+        Self {
+            connect_timeout_ms: 5_000,
+            startup_attempts: 10,
+            initial_backoff_ms: 500,
+            max_backoff_ms: 10_000,
+            health_check_interval_ms: 5_000,
+        }
+    }
 }
 /// Represents the email client settings.
 #[derive(Deserialize, Debug)]
@@ -48,6 +294,94 @@ pub struct EmailClientSettings {
     pub base_url: String,
     /// The sender email address for the email client.
     pub sender: String,
+    /// Internal addresses that receive test sends of an issue.
+    #[serde(default)]
+    pub seed_list: Vec<String>,
+    /// Domains the recipients named in a test-send request must belong to; empty allows any.
+    #[serde(default)]
+    pub test_send_domains: Vec<String>,
+    /// The send-rate limit enforced towards the provider.
+    #[serde(default)]
+    pub rate_limit: RateLimitSettings,
+    /// When to stop calling a failing provider.
+    #[serde(default)]
+    pub circuit_breaker: CircuitBreakerSettings,
+    /// A Postmark-compatible provider used while the primary one is unavailable.
+    #[serde(default)]
+    pub secondary: Option<EmailProviderSettings>,
+    /// How long calls to the providers may take.
+    #[serde(default)]
+    pub timeouts: EmailTimeoutSettings,
+}
+/// Represents the time limits on calls to the email providers, in milliseconds.
+#[derive(Deserialize, Debug, Clone)]
+#[serde(default)]
+pub struct EmailTimeoutSettings {
+    /// The limit for establishing a connection.
+    pub connect_ms: u64,
+    /// The limit between two reads of the response.
+    pub read_ms: u64,
+    /// The limit for the whole request, from connecting to reading the last byte.
+    pub request_ms: u64,
+}
+impl Default for EmailTimeoutSettings {
+    fn default() -> Self {
+        Self {
+            connect_ms: 2_000,
+            read_ms: 5_000,
+            request_ms: 10_000,
+        }
+    }
+}
+/// Represents a fallback email provider.
+#[derive(Deserialize, Debug)]
+pub struct EmailProviderSettings {
+    /// The token for the provider.
+    pub token: Secret<String>,
+    /// The base URL for the provider.
+    pub base_url: String,
+}
+/// Represents the circuit breaker guarding each email provider.
+#[derive(Deserialize, Debug, Clone)]
+pub struct CircuitBreakerSettings {
+    /// Consecutive failures after which the circuit opens.
+    pub failure_threshold: u32,
+    /// Seconds to wait before a trial request is let through an open circuit.
+    pub cooldown_secs: u64,
+}
+impl Default for CircuitBreakerSettings {
+    fn default() -> Self {
+        Self {
+            failure_threshold: 5,
+            cooldown_secs: 30,
+        }
+    }
+}
+/// Represents the token-bucket limit on outgoing emails.
+///
+/// The server and `issues send` keep the bucket in the database, so the limits apply to all
+/// of them together. While the database cannot be reached each process falls back to a
+/// bucket of its own with the same limits.
+#[derive(Deserialize, Debug, Clone)]
+pub struct RateLimitSettings {
+    /// The sustained number of messages sent per second.
+    pub messages_per_second: f64,
+    /// The number of messages that may be sent at once after a quiet period.
+    pub burst: u32,
+    /// How many times a message is retried after the provider answers with HTTP 429, at most
+    /// [`MAX_RETRIES`].
+    pub max_retries: u32,
+}
+/// Upper bound on `email_client.rate_limit.max_retries`; each retry may wait up to a minute.
+pub const MAX_RETRIES: u32 = 10;
+impl Default for RateLimitSettings {
+    fn default() -> Self {
+        Self {
+            messages_per_second: 10.0,
+            burst: 10,
+            max_retries: 3,
+        }
+    }
 }
 pub enum Environment {
     Local,
@@ -55,6 +389,12 @@ pub enum Environment {
     Production,
 }
 impl Environment {
+    /// Reads `APP_ENVIRONMENT`, defaulting to `production`.
+    pub fn current() -> crate::Result<Self> {
+        std::env::var("APP_ENVIRONMENT")
+            .unwrap_or_else(|_| "production".into())
+            .try_into()
+    }
     pub fn as_str(&self) -> &'static str {
         match self {
             Environment::Local => "local",
@@ -76,34 +416,375 @@ impl TryFrom<String> for Environment {
         }
     }
 }
-/// Retrieves the application configuration settings.
-///
-/// This function reads the configuration settings from a TOML file based on the current environment
-/// and returns a `Result` containing the `Settings` struct if successful.
+/// The directory configuration files are read from when none is given.
+pub const DEFAULT_CONFIG_DIR: &str = "configuration";
+/// Prefix of the environment variables overriding settings, e.g. `APP__DATABASE__PORT=8001`.
+const ENV_PREFIX: &str = "APP";
+/// Suffix marking a setting whose value is read from a file, e.g. `password_file`.
+const FILE_SUFFIX: &str = "_file";
+
+/// Retrieves the application configuration settings from [`DEFAULT_CONFIG_DIR`].
 pub fn get_configuration() -> crate::Result<Settings> {
-    let path = std::env::current_dir()?.join("configuration");
-    let environment: Environment = std::env::var("APP_ENVIRONMENT")
-        .unwrap_or_else(|_| "production".into())
-        .try_into()?;
-    let environment_filename = format!("{}.toml", environment.as_str());
-    let env = path.join(environment_filename);
-    let settings = Config::builder()
-        .add_source(config::File::from(env))
-        .build()?;
-    let config = settings.try_deserialize()?;
-    Ok(config)
+    get_configuration_from(Path::new(DEFAULT_CONFIG_DIR))
 }
-impl Settings {
-    pub fn connection_string(&self) -> Secret<String> {
-        Secret::new(format!("{}:{}", self.database.host, self.database.port))
+/// Retrieves the application configuration settings from `dir`.
+///
+/// Sources are layered, later ones winning:
+/// 1. `base.toml`, shared by every environment;
+/// 2. `{APP_ENVIRONMENT}.toml`, defaulting to `production`;
+/// 3. `APP__SECTION__KEY` environment variables.
+///
+/// Any key ending in `_file`, such as `APP__EMAIL_CLIENT__TOKEN_FILE=/run/secrets/postmark`,
+/// sets the key without the suffix to the contents of that file. The result is validated
+/// before it is returned, and errors name the offending key.
+pub fn get_configuration_from(dir: &Path) -> crate::Result<Settings> {
+    load(dir, None)
+}
+/// Loads the settings from `dir`, reading `APP__*` variables from `variables` instead of the
+/// process environment when given, so tests do not have to change the latter.
+fn load(dir: &Path, variables: Option<config::Map<String, String>>) -> crate::Result<Settings> {
+    let environment = Environment::current()?;
+    let builder = Config::builder()
+        .add_source(config::File::from(dir.join("base.toml")))
+        .add_source(config::File::from(
+            dir.join(format!("{}.toml", environment.as_str())),
+        ))
+        .add_source(
+            config::Environment::with_prefix(ENV_PREFIX)
+                .prefix_separator("__")
+                .separator("__")
+                .try_parsing(true)
+                .list_separator(",")
+                .with_list_parse_key("email_client.seed_list")
+                .with_list_parse_key("email_client.test_send_domains")
+                .with_list_parse_key("logging.directives")
+                .source(variables),
+        );
+    let mut secrets = Vec::new();
+    collect_secret_files(&builder.build_cloned()?.collect()?, "", &mut secrets)?;
+    let builder = secrets
+        .into_iter()
+        .try_fold(builder, |builder, (key, value)| {
+            builder.set_override(key, value)
+        })?;
+    let settings: Settings = serde_path_to_error::deserialize(builder.build()?)
+        .map_err(|e| invalid(&e.path().to_string(), e.into_inner()))?;
+    settings.validate(&environment)?;
+    Ok(settings)
+}
+/// Finds every `*_file` key under `table` and reads the secret it points at.
+fn collect_secret_files(
+    table: &config::Map<String, config::Value>,
+    prefix: &str,
+    secrets: &mut Vec<(String, String)>,
+) -> crate::Result<()> {
+    for (key, value) in table {
+        let path = if prefix.is_empty() {
+            key.clone()
+        } else {
+            format!("{prefix}.{key}")
+        };
+        if let Ok(nested) = value.clone().into_table() {
+            collect_secret_files(&nested, &path, secrets)?;
+        } else if let Some(target) = path.strip_suffix(FILE_SUFFIX) {
+            let file = value.clone().into_string().map_err(|e| invalid(&path, e))?;
+            let secret = std::fs::read_to_string(&file).map_err(|e| invalid(&path, e))?;
+            secrets.push((target.to_string(), secret.trim_end().to_string()));
+        }
     }
-    pub fn root(&self) -> Root {
-        Root {
-            username: &self.database.username,
-            password: self.database.password.expose_secret(),
+    Ok(())
+}
+fn invalid(key: &str, reason: impl std::fmt::Display) -> AppError {
+    AppError::InvalidConfiguration(format!("{key}: {reason}"))
+}
+impl Settings {
+    /// Checks the settings that deserialize fine but cannot work.
+    fn validate(&self, environment: &Environment) -> crate::Result<()> {
+        let connection = &self.database.connection;
+        for (key, value) in [
+            ("connect_timeout_ms", connection.connect_timeout_ms),
+            ("startup_attempts", connection.startup_attempts.into()),
+            (
+                "health_check_interval_ms",
+                connection.health_check_interval_ms,
+            ),
+        ] {
+            if value == 0 {
+                return Err(invalid(
+                    &format!("database.connection.{key}"),
+                    "must be positive",
+                ));
+            }
+        }
+        if connection.max_backoff_ms < connection.initial_backoff_ms {
+            return Err(invalid(
+                "database.connection.max_backoff_ms",
+                "must not be below initial_backoff_ms",
+            ));
+        }
+        let subscriptions = &self.subscriptions;
+        for (key, value) in [
+            (
+                "confirmation_ttl_hours",
+                subscriptions.confirmation_ttl_hours,
+            ),
+            ("cleanup_interval_secs", subscriptions.cleanup_interval_secs),
+        ] {
+            if value == 0 {
+                return Err(invalid(&format!("subscriptions.{key}"), "must be positive"));
+            }
+        }
+        if subscriptions.unconfirmed_retention() < subscriptions.confirmation_ttl() {
+            return Err(invalid(
+                "subscriptions.unconfirmed_retention_days",
+                "must cover confirmation_ttl_hours, or valid links would stop working",
+            ));
+        }
+        let application = &self.application;
+        if application.port == application.metrics_port {
+            return Err(invalid(
+                "application.metrics_port",
+                "must differ from application.port",
+            ));
+        }
+        if application.timeouts.request_ms == 0 {
+            return Err(invalid(
+                "application.timeouts.request_ms",
+                "must be positive",
+            ));
+        }
+        if let Some(route) = application.timeouts.routes.iter().find(|(_, ms)| **ms == 0) {
+            return Err(invalid(
+                &format!("application.timeouts.routes.{}", route.0),
+                "must be positive",
+            ));
         }
+        if application.admin_token.expose_secret().trim().is_empty() {
+            return Err(invalid("application.admin_token", "must not be empty"));
+        }
+        reqwest::Url::parse(&application.base_url)
+            .map_err(|e| invalid("application.base_url", e))?;
+        let email = &self.email_client;
+        reqwest::Url::parse(&email.base_url).map_err(|e| invalid("email_client.base_url", e))?;
+        if let Some(secondary) = &email.secondary {
+            reqwest::Url::parse(&secondary.base_url)
+                .map_err(|e| invalid("email_client.secondary.base_url", e))?;
+        }
+        if !validate_email(&email.sender) {
+            return Err(invalid("email_client.sender", "not a valid email address"));
+        }
+        if let Some(recipient) = email.seed_list.iter().find(|r| !validate_email(r)) {
+            return Err(invalid(
+                "email_client.seed_list",
+                format!("{recipient:?} is not a valid email address"),
+            ));
+        }
+        let timeouts = &email.timeouts;
+        for (key, ms) in [
+            ("connect_ms", timeouts.connect_ms),
+            ("read_ms", timeouts.read_ms),
+            ("request_ms", timeouts.request_ms),
+        ] {
+            if ms == 0 {
+                return Err(invalid(
+                    &format!("email_client.timeouts.{key}"),
+                    "must be positive",
+                ));
+            }
+        }
+        if email.rate_limit.messages_per_second <= 0.0 {
+            return Err(invalid(
+                "email_client.rate_limit.messages_per_second",
+                "must be positive",
+            ));
+        }
+        if email.rate_limit.burst == 0 {
+            return Err(invalid("email_client.rate_limit.burst", "must be positive"));
+        }
+        if email.rate_limit.max_retries > MAX_RETRIES {
+            return Err(invalid(
+                "email_client.rate_limit.max_retries",
+                format!("must be at most {MAX_RETRIES}"),
+            ));
+        }
+        if email.circuit_breaker.failure_threshold == 0 {
+            return Err(invalid(
+                "email_client.circuit_breaker.failure_threshold",
+                "must be positive",
+            ));
+        }
+        if self.telemetry.log_pii && !matches!(environment, Environment::Local) {
+            return Err(invalid(
+                "telemetry.log_pii",
+                "can only be enabled in the local environment",
+            ));
+        }
+        Ok(())
     }
     pub fn app_addr(&self) -> String {
         format!("{}:{}", self.application.host, self.application.port)
     }
+    pub fn metrics_addr(&self) -> String {
+        format!(
+            "{}:{}",
+            self.application.host, self.application.metrics_port
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::PathBuf;
+
+    use super::*;
+
+    const BASE: &str = r#"
+        [application]
+        host = "127.0.0.1"
+        port = 8000
+        metrics_port = 9000
+        base_url = "http://localhost:8000"
+        hmac_secret = "from-the-file"
+        admin_token = "from-the-file"
+        [application.timeouts.routes]
+        "/admin/issues/:id/test-send" = 60000
+        [database]
+        username = "root"
+        password = "root"
+        port = 8001
+        host = "localhost"
+        database_name = "newsletter"
+        namespace = "zero2prod"
+        [email_client]
+        token = "from-the-file"
+        base_url = "https://api.postmarkapp.com/"
+        sender = "mail@example.com"
+    "#;
+
+    /// Writes `base.toml` and `production.toml` into a fresh directory.
+    fn config_dir(production: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("zero2prod-config-{}", uuid::Uuid::new_v4()));
+        std::fs::create_dir_all(&dir).unwrap();
+        std::fs::write(dir.join("base.toml"), BASE).unwrap();
+        std::fs::write(dir.join("production.toml"), production).unwrap();
+        dir
+    }
+
+    fn error_message(dir: &Path) -> String {
+        match get_configuration_from(dir) {
+            Err(AppError::InvalidConfiguration(reason)) => reason,
+            other => panic!("expected an invalid configuration, got {other:?}"),
+        }
+    }
+
+    #[test]
+    fn environment_file_then_variables_then_secret_files_win() {
+        let dir = config_dir("[database]\nhost = \"db.internal\"\nport = 9001\n");
+        let token = dir.join("postmark_token");
+        std::fs::write(&token, "from-a-secret\n").unwrap();
+        let variables = config::Map::from_iter([
+            ("APP__DATABASE__PORT".to_string(), "9002".to_string()),
+            (
+                "APP__EMAIL_CLIENT__TOKEN_FILE".to_string(),
+                token.display().to_string(),
+            ),
+            (
+                "APP__EMAIL_CLIENT__SEED_LIST".to_string(),
+                "a@example.com,b@example.com".to_string(),
+            ),
+        ]);
+
+        let settings = load(&dir, Some(variables));
+
+        std::fs::remove_dir_all(&dir).unwrap();
+        let settings = settings.unwrap();
+        assert_eq!(settings.database.host, "db.internal");
+        assert_eq!(
+            settings.application.timeouts.routes["/admin/issues/:id/test-send"],
+            60_000
+        );
+        assert_eq!(settings.database.port, 9002);
+        assert_eq!(settings.email_client.token.expose_secret(), "from-a-secret");
+        assert_eq!(
+            settings.email_client.seed_list,
+            ["a@example.com", "b@example.com"]
+        );
+    }
+
+    #[test]
+    fn run_mode_defaults_to_both() {
+        let both = config_dir("");
+        let worker = config_dir("[application]\nmode = \"worker\"\n");
+
+        let both_mode = get_configuration_from(&both).map(|s| s.application.mode);
+        let worker_mode = get_configuration_from(&worker).map(|s| s.application.mode);
+
+        std::fs::remove_dir_all(&both).unwrap();
+        std::fs::remove_dir_all(&worker).unwrap();
+        assert_eq!(both_mode.unwrap(), RunMode::Both);
+        assert_eq!(worker_mode.unwrap(), RunMode::Worker);
+    }
+
+    #[test]
+    fn type_errors_name_the_offending_key() {
+        let dir = config_dir("[application]\nmetrics_port = \"nine thousand\"\n");
+
+        let message = error_message(&dir);
+
+        std::fs::remove_dir_all(&dir).unwrap();
+        assert!(
+            message.starts_with("application.metrics_port:"),
+            "{message}"
+        );
+    }
+
+    #[test]
+    fn unconfirmed_subscribers_outlive_their_confirmation_links() {
+        let dir = config_dir(
+            "[subscriptions]\nconfirmation_ttl_hours = 72\nunconfirmed_retention_days = 2\n",
+        );
+
+        let message = error_message(&dir);
+
+        std::fs::remove_dir_all(&dir).unwrap();
+        assert!(
+            message.starts_with("subscriptions.unconfirmed_retention_days:"),
+            "{message}"
+        );
+    }
+
+    #[test]
+    fn rate_limit_retries_are_bounded() {
+        let dir = config_dir(
+            "[email_client.rate_limit]\nmessages_per_second = 10.0\nburst = 10\nmax_retries = 50\n",
+        );
+
+        let message = error_message(&dir);
+
+        std::fs::remove_dir_all(&dir).unwrap();
+        assert!(
+            message.starts_with("email_client.rate_limit.max_retries:"),
+            "{message}"
+        );
+    }
+
+    #[test]
+    fn a_blank_admin_token_is_rejected() {
+        let dir = config_dir("[application]\nadmin_token = \" \"\n");
+
+        let message = error_message(&dir);
+
+        std::fs::remove_dir_all(&dir).unwrap();
+        assert!(message.starts_with("application.admin_token:"), "{message}");
+    }
+
+    #[test]
+    fn unusable_values_name_the_offending_key() {
+        let dir = config_dir("[email_client]\nsender = \"not an email\"\n");
+
+        let message = error_message(&dir);
+
+        std::fs::remove_dir_all(&dir).unwrap();
+        assert!(message.starts_with("email_client.sender:"), "{message}");
+    }
 }
@@ -0,0 +1,109 @@
+use pulldown_cmark::{html, Options, Parser};
+
+use crate::Result;
+
+/// Line width used when wrapping the plain-text alternative.
+const TEXT_WIDTH: usize = 80;
+/// Stylesheet applied to every rendered issue before it is inlined.
+const EMAIL_CSS: &str = "
+body { font-family: Helvetica, Arial, sans-serif; font-size: 16px; line-height: 1.5; color: #222222; }
+h1, h2, h3 { color: #111111; line-height: 1.25; }
+a { color: #1a73e8; }
+pre, code { font-family: Menlo, Consolas, monospace; background-color: #f5f5f5; }
+blockquote { margin: 0; padding-left: 12px; border-left: 3px solid #dddddd; color: #555555; }
+table { border-collapse: collapse; }
+th, td { border: 1px solid #dddddd; padding: 4px 8px; }
+";
+
+/// An email body rendered into both of its representations.
+#[derive(Debug, Clone)]
+pub struct RenderedContent {
+    pub html: String,
+    pub text: String,
+}
+
+/// Renders Markdown into sanitized HTML with inlined CSS, plus a plain-text alternative.
+pub fn render_markdown(markdown: &str) -> Result<RenderedContent> {
+    let parser = Parser::new_ext(markdown, Options::all());
+    let mut unsafe_html = String::new();
+    html::push_html(&mut unsafe_html, parser);
+    let body = ammonia::clean(&unsafe_html);
+    let text = html_to_text(&body)?;
+    let document = format!(
+        "<!DOCTYPE html><html><head><style>{EMAIL_CSS}</style></head><body>{body}</body></html>"
+    );
+    let html = css_inline::inline(&document)?;
+    Ok(RenderedContent { html, text })
+}
+
+/// Derives a readable plain-text alternative from an HTML email body.
+///
+/// Links are rendered as numbered footnotes, headings keep their markers and
+/// tables are flattened so that every cell ends up on its own line.
+pub fn html_to_text(html: &str) -> Result<String> {
+    let text = html2text::config::plain()
+        .link_footnotes(true)
+        .raw_mode(true)
+        .string_from_read(html.as_bytes(), TEXT_WIDTH)?;
+    Ok(text)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{
+        content::{html_to_text, render_markdown},
+        Result,
+    };
+
+    #[test]
+    fn links_become_footnotes() -> Result<()> {
+        let html = r#"<p>Read the <a href="https://example.com/issues/1">latest issue</a> or
+            <a href="https://example.com/archive">browse the archive</a>.</p>"#;
+        insta::assert_snapshot!(html_to_text(html)?);
+        Ok(())
+    }
+    #[test]
+    fn headings_stay_visible() -> Result<()> {
+        let html = "<h1>Weekly digest</h1><p>Intro</p><h2>News</h2><p>Body</p>";
+        insta::assert_snapshot!(html_to_text(html)?);
+        Ok(())
+    }
+    #[test]
+    fn tables_are_flattened() -> Result<()> {
+        let html = "<table>
+            <tr><th>Name</th><th>Price</th></tr>
+            <tr><td>Rust book</td><td>$39</td></tr>
+            <tr><td>Sticker</td><td>$2</td></tr>
+        </table>";
+        insta::assert_snapshot!(html_to_text(html)?);
+        Ok(())
+    }
+    #[test]
+    fn full_newsletter() -> Result<()> {
+        let html = r#"<html><head><style>p { color: red; }</style></head><body>
+            <h1>Hello, subscriber!</h1>
+            <p>This week we shipped <strong>three</strong> features:</p>
+            <ul><li>Drafts</li><li>Scheduling</li><li>Previews</li></ul>
+            <p>See the <a href="https://example.com/changelog">changelog</a>.</p>
+        </body></html>"#;
+        insta::assert_snapshot!(html_to_text(html)?);
+        Ok(())
+    }
+    #[test]
+    fn markdown_is_rendered_with_inlined_css() -> Result<()> {
+        let rendered = render_markdown("# Title\n\nSome [link](https://example.com).")?;
+        assert!(rendered.html.contains("<h1 style="));
+        assert!(rendered.html.contains(r#"<a href="https://example.com""#));
+        assert!(!rendered.html.contains("<style>"));
+        insta::assert_snapshot!(rendered.text);
+        Ok(())
+    }
+    #[test]
+    fn markdown_is_sanitized() -> Result<()> {
+        let markdown = "Hello <script>alert(1)</script><img src=x onerror=alert(1)>";
+        let rendered = render_markdown(markdown)?;
+        assert!(!rendered.html.contains("<script"));
+        assert!(!rendered.html.contains("onerror"));
+        Ok(())
+    }
+}
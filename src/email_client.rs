@@ -1,64 +1,258 @@
-use fake::{Fake, Faker};
+use std::{sync::Arc, time::Duration};
+
+use reqwest::{
+    header::{HeaderMap, RETRY_AFTER},
+    StatusCode,
+};
 use secrecy::{ExposeSecret, Secret};
-use serde::Serialize;
+use serde::{Deserialize, Serialize};
 
-use crate::{configuration::Settings, Result};
+use crate::{
+    circuit_breaker::{CircuitBreaker, CircuitState},
+    configuration::EmailClientSettings,
+    content::html_to_text,
+    rate_limiter::RateLimiter,
+    request_id::{self, REQUEST_ID_HEADER},
+    telemetry::inject_trace_context,
+    AppError, Result, Storage,
+};
 const HEADER: &str = "X-Postmark-Server-Token";
+/// Postmark error code for recipients marked inactive after a hard bounce or spam complaint.
+pub const INACTIVE_RECIPIENT: i64 = 406;
+/// Delay before the first retry when a 429 response carries no `Retry-After` header.
+const INITIAL_BACKOFF: Duration = Duration::from_millis(500);
+/// Longest wait before a retry, whatever the provider's `Retry-After` asks for.
+const MAX_BACKOFF: Duration = Duration::from_secs(60);
 #[derive(Clone)]
 pub struct EmailClient {
     http_client: reqwest::Client,
-    base_url: reqwest::Url,
+    /// Providers in order of preference; later ones are only used while earlier ones fail.
+    providers: Vec<Provider>,
     sender: String,
+    seed_list: Vec<String>,
+    test_send_domains: Vec<String>,
+    rate_limiter: Arc<RateLimiter>,
+    max_retries: u32,
+}
+#[derive(Clone)]
+struct Provider {
+    name: &'static str,
+    base_url: reqwest::Url,
     token: Secret<String>,
+    breaker: Arc<CircuitBreaker>,
+}
+/// The circuit state of a single provider, as reported on the health endpoint.
+#[derive(Serialize, Debug, Clone)]
+pub struct ProviderStatus {
+    pub name: &'static str,
+    pub circuit: CircuitState,
 }
 impl EmailClient {
-    pub fn new(config: &Settings) -> Result<Self> {
+    pub fn new(settings: &EmailClientSettings) -> Result<Self> {
+        let timeouts = &settings.timeouts;
         let http_client = reqwest::Client::builder()
-            .timeout(std::time::Duration::from_secs(10))
+            .connect_timeout(Duration::from_millis(timeouts.connect_ms))
+            .read_timeout(Duration::from_millis(timeouts.read_ms))
+            .timeout(Duration::from_millis(timeouts.request_ms))
             .build()?;
+        let mut providers = vec![Provider {
+            name: "primary",
+            base_url: reqwest::Url::parse(&settings.base_url)?,
+            token: settings.token.clone(),
+            breaker: Arc::new(CircuitBreaker::new("primary", &settings.circuit_breaker)),
+        }];
+        if let Some(secondary) = &settings.secondary {
+            providers.push(Provider {
+                name: "secondary",
+                base_url: reqwest::Url::parse(&secondary.base_url)?,
+                token: secondary.token.clone(),
+                breaker: Arc::new(CircuitBreaker::new("secondary", &settings.circuit_breaker)),
+            });
+        }
         Ok(Self {
             http_client,
-            base_url: reqwest::Url::parse(&config.email_client.base_url)?,
-            sender: config.email_client.sender.clone(),
-            token: config.email_client.token.clone(),
+            providers,
+            sender: settings.sender.clone(),
+            seed_list: settings.seed_list.clone(),
+            test_send_domains: settings.test_send_domains.clone(),
+            rate_limiter: Arc::new(RateLimiter::new(&settings.rate_limit)),
+            max_retries: settings.rate_limit.max_retries,
         })
     }
-    pub fn for_tests(server: String, sender: String) -> Result<Self> {
-        let http_client = reqwest::Client::builder()
-            .timeout(std::time::Duration::from_millis(200))
-            .build()?;
+    /// Like [`EmailClient::new`], with the send-rate limit shared through `storage` by every
+    /// process using the same database.
+    pub fn with_shared_rate_limit(
+        settings: &EmailClientSettings,
+        storage: Storage,
+    ) -> Result<Self> {
         Ok(Self {
-            http_client,
-            base_url: reqwest::Url::parse(&server)?,
-            sender,
-            token: Secret::new(Faker.fake()),
+            rate_limiter: Arc::new(RateLimiter::shared(&settings.rate_limit, storage)),
+            ..Self::new(settings)?
         })
     }
+    /// Internal addresses that receive test sends of an issue.
+    pub fn seed_list(&self) -> &[String] {
+        &self.seed_list
+    }
+    /// Whether a test send may go to `recipient`, per `email_client.test_send_domains`.
+    pub fn accepts_test_recipient(&self, recipient: &str) -> bool {
+        let domain = recipient.rsplit_once('@').map_or("", |(_, domain)| domain);
+        self.test_send_domains.is_empty()
+            || self
+                .test_send_domains
+                .iter()
+                .any(|allowed| allowed.eq_ignore_ascii_case(domain))
+    }
+    /// The circuit state of every configured provider.
+    pub fn provider_statuses(&self) -> Vec<ProviderStatus> {
+        self.providers
+            .iter()
+            .map(|p| ProviderStatus {
+                name: p.name,
+                circuit: p.breaker.state(),
+            })
+            .collect()
+    }
+    /// Sends an email and returns the message id the provider assigned, if any.
+    ///
+    /// Providers whose circuit is open are skipped; when a provider fails the next one is tried.
+    /// When `text_body` is `None` the plain-text alternative is derived from `html_body`.
+    #[tracing::instrument(name = "Sending email", skip_all, fields(subject = subject))]
     pub async fn send_email(
         &self,
         recipient: String,
         subject: &str,
         html_body: &str,
-        text_body: &str,
-    ) -> Result<()> {
-        let url = self.base_url.join("email")?;
+        text_body: Option<&str>,
+    ) -> Result<Option<String>> {
+        let text_body = match text_body {
+            Some(text) => text.to_string(),
+            None => html_to_text(html_body)?,
+        };
         let request_body = SendEmailRequest {
             from: &self.sender,
             to: &recipient,
             subject,
             html_body,
-            text_body,
+            text_body: &text_body,
         };
-        self.http_client
-            .post(url)
-            .header(HEADER, self.token.expose_secret())
-            .json(&request_body)
-            .send()
-            .await?
-            .error_for_status()?;
-        Ok(())
+        let mut last_error = None;
+        for provider in &self.providers {
+            if !provider.breaker.try_acquire() {
+                tracing::debug!(
+                    provider = provider.name,
+                    "Circuit is open, skipping provider"
+                );
+                continue;
+            }
+            match self.send_with(provider, &request_body).await {
+                Err(e) if is_provider_failure(&e) => {
+                    metrics::counter!("emails_failed_total", "provider" => provider.name)
+                        .increment(1);
+                    provider.breaker.record_failure();
+                    tracing::warn!(provider = provider.name, error = ?e, "Email provider failed");
+                    last_error = Some(e);
+                }
+                outcome => {
+                    let counter = if outcome.is_ok() {
+                        "emails_sent_total"
+                    } else {
+                        "emails_failed_total"
+                    };
+                    metrics::counter!(counter, "provider" => provider.name).increment(1);
+                    provider.breaker.record_success();
+                    return outcome;
+                }
+            }
+        }
+        Err(last_error.unwrap_or(AppError::EmailUnavailable))
+    }
+    async fn send_with(
+        &self,
+        provider: &Provider,
+        request_body: &SendEmailRequest<'_>,
+    ) -> Result<Option<String>> {
+        let url = provider.base_url.join("email")?;
+        let mut attempt = 0;
+        let response = loop {
+            self.rate_limiter.acquire().await;
+            let mut headers = HeaderMap::new();
+            inject_trace_context(&mut headers);
+            if let Some(id) = request_id::current().and_then(|id| id.parse().ok()) {
+                headers.insert(&REQUEST_ID_HEADER, id);
+            }
+            let response = self
+                .http_client
+                .post(url.clone())
+                .headers(headers)
+                .header(HEADER, provider.token.expose_secret())
+                .json(request_body)
+                .send()
+                .await?;
+            if response.status() != StatusCode::TOO_MANY_REQUESTS || attempt >= self.max_retries {
+                break response;
+            }
+            let delay = backoff_delay(attempt, retry_after(&response));
+            tracing::warn!(
+                provider = provider.name,
+                ?delay,
+                attempt,
+                "Email provider is rate limiting us, backing off"
+            );
+            self.rate_limiter.back_off(delay).await;
+            attempt += 1;
+        };
+        let status = response.status();
+        if !status.is_success() {
+            let error = response.json::<ProviderErrorResponse>().await.ok();
+            return Err(AppError::EmailRejected {
+                status: status.as_u16(),
+                error_code: error.as_ref().map(|e| e.error_code),
+                message: error.map(|e| e.message).unwrap_or_default(),
+            });
+        }
+        let sent = response.json::<SendEmailResponse>().await.ok();
+        Ok(sent.and_then(|s| s.message_id))
+    }
+}
+/// Whether an error means the provider is unhealthy, as opposed to it rejecting this message.
+fn is_provider_failure(error: &AppError) -> bool {
+    match error {
+        AppError::EmailRejected { status, .. } => {
+            *status >= 500 || *status == StatusCode::TOO_MANY_REQUESTS.as_u16()
+        }
+        _ => true,
     }
 }
+/// How long to wait before retry number `attempt`: what `Retry-After` asked for if anything,
+/// doubling from [`INITIAL_BACKOFF`] otherwise, and never more than [`MAX_BACKOFF`].
+fn backoff_delay(attempt: u32, retry_after: Option<Duration>) -> Duration {
+    retry_after
+        .unwrap_or_else(|| INITIAL_BACKOFF.saturating_mul(2u32.saturating_pow(attempt)))
+        .min(MAX_BACKOFF)
+}
+/// Reads a `Retry-After` header given in seconds.
+fn retry_after(response: &reqwest::Response) -> Option<Duration> {
+    response
+        .headers()
+        .get(RETRY_AFTER)?
+        .to_str()
+        .ok()?
+        .parse()
+        .ok()
+        .map(Duration::from_secs)
+}
+#[derive(Deserialize)]
+struct SendEmailResponse {
+    #[serde(rename = "MessageID")]
+    message_id: Option<String>,
+}
+#[derive(Deserialize)]
+#[serde(rename_all = "PascalCase")]
+struct ProviderErrorResponse {
+    error_code: i64,
+    message: String,
+}
 #[derive(Serialize)]
 #[serde(rename_all = "PascalCase")]
 struct SendEmailRequest<'a> {
@@ -70,6 +264,8 @@ struct SendEmailRequest<'a> {
 }
 #[cfg(test)]
 mod tests {
+    use std::time::Duration;
+
     use fake::{
         faker::{
             internet::en::SafeEmail,
@@ -97,7 +293,18 @@ mod tests {
         }
     }
 
-    use crate::{email_client::HEADER, EmailClient, Result};
+    use fake::Faker;
+    use secrecy::Secret;
+
+    use crate::{
+        circuit_breaker::CircuitState,
+        configuration::{
+            CircuitBreakerSettings, EmailClientSettings, EmailProviderSettings,
+            EmailTimeoutSettings,
+        },
+        email_client::{backoff_delay, HEADER, INACTIVE_RECIPIENT, MAX_BACKOFF},
+        AppError, EmailClient, Result,
+    };
     struct MockData {
         mock_server: MockServer,
         email_client: EmailClient,
@@ -105,10 +312,28 @@ mod tests {
         subject: String,
         content: String,
     }
+    /// Settings pointing at a mock server, with timeouts short enough to keep tests fast.
+    fn test_settings(base_url: String, sender: String) -> EmailClientSettings {
+        EmailClientSettings {
+            token: Secret::new(Faker.fake()),
+            base_url,
+            sender,
+            seed_list: Vec::new(),
+            test_send_domains: Vec::new(),
+            rate_limit: Default::default(),
+            circuit_breaker: Default::default(),
+            secondary: None,
+            timeouts: EmailTimeoutSettings {
+                connect_ms: 200,
+                read_ms: 200,
+                request_ms: 200,
+            },
+        }
+    }
     async fn generate_test_data() -> Result<MockData> {
         let mock_server = MockServer::start().await;
         let sender: String = SafeEmail().fake();
-        let email_client = EmailClient::for_tests(mock_server.uri(), sender.clone())?;
+        let email_client = EmailClient::new(&test_settings(mock_server.uri(), sender))?;
         let subscriber_email: String = SafeEmail().fake();
         let subject: String = Sentence(1..2).fake();
         let content: String = Paragraph(1..10).fake();
@@ -138,7 +363,7 @@ mod tests {
                 mock_data.subscriber_email,
                 &mock_data.subject,
                 &mock_data.content,
-                &mock_data.content,
+                Some(&mock_data.content),
             )
             .await?;
         Ok(())
@@ -157,7 +382,7 @@ mod tests {
                 mock_data.subscriber_email,
                 &mock_data.subject,
                 &mock_data.content,
-                &mock_data.content,
+                Some(&mock_data.content),
             )
             .await;
         assert!(outcome.is_ok());
@@ -177,7 +402,7 @@ mod tests {
                 mock_data.subscriber_email,
                 &mock_data.subject,
                 &mock_data.content,
-                &mock_data.content,
+                Some(&mock_data.content),
             )
             .await;
         assert!(outcome.is_err());
@@ -198,7 +423,7 @@ mod tests {
                 mock_data.subscriber_email,
                 &mock_data.subject,
                 &mock_data.content,
-                &mock_data.content,
+                Some(&mock_data.content),
             )
             .await;
         assert!(outcome.is_err());
@@ -222,10 +447,243 @@ mod tests {
                 mock_data.subscriber_email,
                 &mock_data.subject,
                 &mock_data.content,
+                Some(&mock_data.content),
+            )
+            .await;
+        assert!(outcome.is_ok());
+        Ok(())
+    }
+    struct TextBodyMatcher(String);
+
+    impl wiremock::Match for TextBodyMatcher {
+        fn matches(&self, request: &Request) -> bool {
+            serde_json::from_slice::<serde_json::Value>(&request.body).is_ok_and(|body| {
+                body.get("TextBody").and_then(|t| t.as_str()) == Some(self.0.as_str())
+            })
+        }
+    }
+    #[tokio::test]
+    async fn send_email_derives_text_body_from_html_when_missing() -> Result<()> {
+        let mock_data = generate_test_data().await?;
+        let html_body = r#"<h1>Hi</h1><p>Visit <a href="https://example.com">us</a>.</p>"#;
+        let expected = crate::content::html_to_text(html_body)?;
+        Mock::given(path("/email"))
+            .and(method("POST"))
+            .and(TextBodyMatcher(expected))
+            .respond_with(ResponseTemplate::new(200))
+            .expect(1)
+            .mount(&mock_data.mock_server)
+            .await;
+        mock_data
+            .email_client
+            .send_email(
+                mock_data.subscriber_email,
+                &mock_data.subject,
+                html_body,
+                None,
+            )
+            .await?;
+        Ok(())
+    }
+    #[tokio::test]
+    async fn send_email_returns_the_provider_message_id() -> Result<()> {
+        let mock_data = generate_test_data().await?;
+        let response = ResponseTemplate::new(200).set_body_json(serde_json::json!({
+            "To": mock_data.subscriber_email,
+            "SubmittedAt": "2024-05-01T12:00:00Z",
+            "MessageID": "b7bc2f4a-e38e-4336-af7d-e6c392c2f817",
+            "ErrorCode": 0,
+            "Message": "OK"
+        }));
+        Mock::given(any())
+            .respond_with(response)
+            .expect(1)
+            .mount(&mock_data.mock_server)
+            .await;
+        let message_id = mock_data
+            .email_client
+            .send_email(
+                mock_data.subscriber_email,
+                &mock_data.subject,
+                &mock_data.content,
+                Some(&mock_data.content),
+            )
+            .await?;
+        assert_eq!(
+            message_id.as_deref(),
+            Some("b7bc2f4a-e38e-4336-af7d-e6c392c2f817")
+        );
+        Ok(())
+    }
+    #[tokio::test]
+    async fn send_email_reports_the_provider_error_code() -> Result<()> {
+        let mock_data = generate_test_data().await?;
+        let response = ResponseTemplate::new(422).set_body_json(serde_json::json!({
+            "ErrorCode": INACTIVE_RECIPIENT,
+            "Message": "You tried to send to recipient(s) that have been marked as inactive."
+        }));
+        Mock::given(any())
+            .respond_with(response)
+            .expect(1)
+            .mount(&mock_data.mock_server)
+            .await;
+        let outcome = mock_data
+            .email_client
+            .send_email(
+                mock_data.subscriber_email,
+                &mock_data.subject,
+                &mock_data.content,
+                Some(&mock_data.content),
+            )
+            .await;
+        assert!(matches!(
+            outcome,
+            Err(AppError::EmailRejected {
+                status: 422,
+                error_code: Some(INACTIVE_RECIPIENT),
+                ..
+            })
+        ));
+        Ok(())
+    }
+    #[tokio::test]
+    async fn send_email_retries_after_a_429() -> Result<()> {
+        let mock_data = generate_test_data().await?;
+        Mock::given(any())
+            .respond_with(ResponseTemplate::new(429).insert_header("Retry-After", "0"))
+            .up_to_n_times(1)
+            .expect(1)
+            .mount(&mock_data.mock_server)
+            .await;
+        Mock::given(any())
+            .respond_with(ResponseTemplate::new(200))
+            .expect(1)
+            .mount(&mock_data.mock_server)
+            .await;
+        let outcome = mock_data
+            .email_client
+            .send_email(
+                mock_data.subscriber_email,
+                &mock_data.subject,
                 &mock_data.content,
+                Some(&mock_data.content),
             )
             .await;
         assert!(outcome.is_ok());
         Ok(())
     }
+    #[tokio::test]
+    async fn send_email_gives_up_after_repeated_429s() -> Result<()> {
+        let mock_data = generate_test_data().await?;
+        Mock::given(any())
+            .respond_with(ResponseTemplate::new(429).insert_header("Retry-After", "0"))
+            .expect(4)
+            .mount(&mock_data.mock_server)
+            .await;
+        let outcome = mock_data
+            .email_client
+            .send_email(
+                mock_data.subscriber_email,
+                &mock_data.subject,
+                &mock_data.content,
+                Some(&mock_data.content),
+            )
+            .await;
+        assert!(matches!(
+            outcome,
+            Err(AppError::EmailRejected { status: 429, .. })
+        ));
+        Ok(())
+    }
+    #[tokio::test]
+    async fn send_email_fails_over_to_the_secondary_provider() -> Result<()> {
+        let primary = MockServer::start().await;
+        let secondary = MockServer::start().await;
+        Mock::given(any())
+            .respond_with(ResponseTemplate::new(500))
+            .expect(2)
+            .mount(&primary)
+            .await;
+        Mock::given(path("/email"))
+            .respond_with(ResponseTemplate::new(200))
+            .expect(3)
+            .mount(&secondary)
+            .await;
+        let settings = EmailClientSettings {
+            circuit_breaker: CircuitBreakerSettings {
+                failure_threshold: 2,
+                cooldown_secs: 60,
+            },
+            secondary: Some(EmailProviderSettings {
+                token: Secret::new(Faker.fake()),
+                base_url: secondary.uri(),
+            }),
+            ..test_settings(primary.uri(), SafeEmail().fake())
+        };
+        let email_client = EmailClient::new(&settings)?;
+        for _ in 0..3 {
+            let recipient: String = SafeEmail().fake();
+            email_client
+                .send_email(recipient, "Subject", "<p>Body</p>", None)
+                .await?;
+        }
+        let statuses = email_client.provider_statuses();
+        assert_eq!(statuses[0].circuit, CircuitState::Open);
+        assert_eq!(statuses[1].circuit, CircuitState::Closed);
+        Ok(())
+    }
+    #[tokio::test]
+    async fn rejected_recipients_do_not_trip_the_circuit() -> Result<()> {
+        let mock_data = generate_test_data().await?;
+        Mock::given(any())
+            .respond_with(ResponseTemplate::new(422))
+            .expect(6)
+            .mount(&mock_data.mock_server)
+            .await;
+        for _ in 0..6 {
+            let outcome = mock_data
+                .email_client
+                .send_email(
+                    mock_data.subscriber_email.clone(),
+                    &mock_data.subject,
+                    &mock_data.content,
+                    None,
+                )
+                .await;
+            assert!(outcome.is_err());
+        }
+        assert_eq!(
+            mock_data.email_client.provider_statuses()[0].circuit,
+            CircuitState::Closed
+        );
+        Ok(())
+    }
+    #[test]
+    fn test_recipients_are_limited_to_the_allowed_domains() -> Result<()> {
+        let mut settings = test_settings("http://localhost/".into(), "mail@example.com".into());
+        assert!(EmailClient::new(&settings)?.accepts_test_recipient("anyone@gmail.com"));
+
+        settings.test_send_domains = vec!["example.com".into()];
+        let email_client = EmailClient::new(&settings)?;
+        assert!(email_client.accepts_test_recipient("editor@Example.com"));
+        assert!(!email_client.accepts_test_recipient("anyone@gmail.com"));
+        assert!(!email_client.accepts_test_recipient("editor@example.com.evil.net"));
+        Ok(())
+    }
+    #[test]
+    fn backoff_doubles_and_is_capped() {
+        let delays: Vec<_> = [0, 1, 2, 6, 7, 40]
+            .into_iter()
+            .map(|attempt| backoff_delay(attempt, None).as_millis())
+            .collect();
+        assert_eq!(delays, [500, 1000, 2000, 32_000, 60_000, 60_000]);
+        assert_eq!(
+            backoff_delay(0, Some(Duration::from_secs(5))),
+            Duration::from_secs(5)
+        );
+        assert_eq!(
+            backoff_delay(0, Some(Duration::from_secs(86_400))),
+            MAX_BACKOFF
+        );
+    }
 }
@@ -1,7 +1,11 @@
 use axum::{
     http::StatusCode,
     response::{IntoResponse, Response},
+    Json,
 };
+use serde::Serialize;
+
+use crate::request_id;
 #[derive(Debug)]
 pub enum AppError {
     Custom(anyhow::Error),
@@ -11,33 +15,95 @@ pub enum AppError {
     EnvError,
     InvalidName,
     InvalidEmail,
+    InvalidTitle,
+    IssueNotFound,
+    IssueNotEditable,
+    InvalidSendTime,
+    NoRecipients,
+    InvalidRecipient(String),
+    TooManyRecipients(usize),
+    EmailRejected {
+        status: u16,
+        error_code: Option<i64>,
+        message: String,
+    },
+    EmailUnavailable,
+    InvalidConfiguration(String),
+    InvalidConfirmationToken,
+    InvalidUnsubscribeLink,
+    Unauthorized,
+}
+/// The JSON body returned for every error, tagged with the request id so reports can be traced.
+#[derive(Serialize, Debug)]
+struct ErrorBody {
+    error: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    request_id: Option<String>,
 }
 impl IntoResponse for AppError {
     fn into_response(self) -> Response {
-        match self {
-            AppError::Custom(e) => {
-                (StatusCode::INTERNAL_SERVER_ERROR, format!("Error: {e}")).into_response()
-            }
+        let (status, error) = match self {
+            AppError::Custom(e) => (StatusCode::INTERNAL_SERVER_ERROR, format!("Error: {e}")),
             AppError::EmailAlreadyExists => {
-                (StatusCode::BAD_REQUEST, "Email already exists").into_response()
+                (StatusCode::BAD_REQUEST, "Email already exists".into())
             }
-            AppError::DatabaseError => {
-                (StatusCode::INTERNAL_SERVER_ERROR, "Database error").into_response()
-            }
-            AppError::UserNotFound => (StatusCode::BAD_REQUEST, "User not found").into_response(),
+            AppError::DatabaseError => (StatusCode::INTERNAL_SERVER_ERROR, "Database error".into()),
+            AppError::UserNotFound => (StatusCode::BAD_REQUEST, "User not found".into()),
             AppError::EnvError => (
                 StatusCode::INTERNAL_SERVER_ERROR,
-                "Wrong environment variable APP_ENVIRONMENT",
-            )
-                .into_response(),
-            AppError::InvalidName => (StatusCode::BAD_REQUEST, "Invalid name").into_response(),
-            AppError::InvalidEmail => (StatusCode::BAD_REQUEST, "Invalid email").into_response(),
+                "Wrong environment variable APP_ENVIRONMENT".into(),
+            ),
+            AppError::InvalidName => (StatusCode::BAD_REQUEST, "Invalid name".into()),
+            AppError::InvalidEmail => (StatusCode::BAD_REQUEST, "Invalid email".into()),
+            AppError::InvalidTitle => (StatusCode::BAD_REQUEST, "Invalid title".into()),
+            AppError::IssueNotEditable => (
+                StatusCode::CONFLICT,
+                "Issue can no longer be changed".into(),
+            ),
+            AppError::InvalidSendTime => (
+                StatusCode::BAD_REQUEST,
+                "Send time must be in the future".into(),
+            ),
+            AppError::EmailRejected { .. } => (
+                StatusCode::BAD_GATEWAY,
+                "Email provider rejected the message".into(),
+            ),
+            AppError::EmailUnavailable => (
+                StatusCode::SERVICE_UNAVAILABLE,
+                "Email provider unavailable".into(),
+            ),
+            AppError::InvalidConfiguration(reason) => (
+                StatusCode::INTERNAL_SERVER_ERROR,
+                format!("Invalid configuration: {reason}"),
+            ),
+            AppError::NoRecipients => (StatusCode::BAD_REQUEST, "No recipients were given".into()),
+            AppError::InvalidRecipient(recipient) => (
+                StatusCode::BAD_REQUEST,
+                format!("Invalid recipient: {recipient}"),
+            ),
+            AppError::TooManyRecipients(limit) => (
+                StatusCode::BAD_REQUEST,
+                format!("At most {limit} recipients may be named"),
+            ),
+            AppError::InvalidConfirmationToken => (
+                StatusCode::UNAUTHORIZED,
+                "Unknown or expired confirmation link".into(),
+            ),
+            AppError::InvalidUnsubscribeLink => {
+                (StatusCode::UNAUTHORIZED, "Invalid unsubscribe link".into())
+            }
+            AppError::Unauthorized => (StatusCode::UNAUTHORIZED, "Unauthorized".into()),
+            AppError::IssueNotFound => (StatusCode::NOT_FOUND, "Issue not found".into()),
             // _ => (
             //     StatusCode::INTERNAL_SERVER_ERROR,
             //     format!("Error: {self:?}"),
-            // )
-            //     .into_response(),
-        }
+            // ),
+        };
+        let body = ErrorBody {
+            error,
+            request_id: request_id::current(),
+        };
+        (status, Json(body)).into_response()
     }
 }
 
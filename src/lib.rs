@@ -1,14 +1,24 @@
+pub mod authentication;
+pub mod circuit_breaker;
+pub mod cli;
 pub mod configuration;
+pub mod content;
 mod error;
+pub mod redaction;
+pub mod request_id;
 pub mod routes;
+pub mod seed;
+pub mod shutdown;
 pub mod startup;
 pub use error::{AppError, Result};
 mod models;
 pub use models::*;
 mod storage;
-pub use storage::Storage;
+pub use storage::{Lease, Storage};
 pub mod telemetry;
 mod validator;
 pub use validator::validate_name;
-mod email_client;
+pub mod email_client;
+mod rate_limiter;
 pub use email_client::EmailClient;
+pub mod workers;
@@ -1,18 +1,7 @@
-use tokio::net::TcpListener;
-use zero2prod::{
-    startup::{app, run},
-    telemetry::{get_subscriber, init_subscriber},
-    Result,
-};
+use clap::Parser;
+use zero2prod::{cli::Cli, Result};
+
 #[tokio::main]
 async fn main() -> Result<()> {
-    let subscriber = get_subscriber("zero2prod", "info", std::io::stdout);
-    init_subscriber(subscriber);
-    let configuration = zero2prod::configuration::get_configuration().unwrap();
-    let listener = TcpListener::bind(&configuration.app_addr()).await?;
-    let email_client = zero2prod::EmailClient::new(&configuration)?;
-    let db = zero2prod::Storage::init(configuration).await?;
-    let app = app(db, email_client);
-    run(listener, app).await?;
-    Ok(())
+    Cli::parse().run().await
 }
@@ -0,0 +1,61 @@
+use serde::{Deserialize, Serialize};
+use surrealdb::sql::{Datetime, Thing};
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum DeliveryStatus {
+    Queued,
+    Sent,
+    Failed,
+    /// The provider refused the recipient because earlier messages bounced.
+    Bounced,
+    /// The subscriber left the list before the delivery was attempted.
+    Suppressed,
+    /// The issue was cancelled before the delivery was attempted.
+    Cancelled,
+}
+/// A single issue queued for, or delivered to, a single subscriber.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Delivery {
+    pub id: Thing,
+    pub issue: Thing,
+    pub subscriber: Thing,
+    pub status: DeliveryStatus,
+    /// The message id the provider assigned to the email.
+    pub message_id: Option<String>,
+    pub last_error: Option<String>,
+    pub created_at: Datetime,
+    pub updated_at: Datetime,
+}
+impl Delivery {
+    /// The lease a worker holds while sending this delivery.
+    pub fn lease_name(&self) -> String {
+        format!("delivery:{}", self.id.id.to_raw())
+    }
+}
+/// What happened when a delivery was attempted.
+#[derive(Debug, Clone)]
+pub struct DeliveryOutcome {
+    pub status: DeliveryStatus,
+    pub message_id: Option<String>,
+    pub last_error: Option<String>,
+}
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct DeliveryCounts {
+    pub queued: u64,
+    pub sent: u64,
+    pub failed: u64,
+    pub bounced: u64,
+    pub suppressed: u64,
+    pub cancelled: u64,
+}
+#[derive(Serialize, Debug, Clone)]
+pub struct DeliveryReport {
+    pub counts: DeliveryCounts,
+    /// Failed and bounced deliveries, oldest first.
+    pub failures: Vec<Delivery>,
+}
+#[derive(Serialize, Debug, Clone)]
+pub struct RequeueReport {
+    pub requeued: usize,
+}
@@ -0,0 +1,161 @@
+use serde::{Deserialize, Serialize};
+use surrealdb::sql::{Datetime, Id, Thing};
+
+use crate::{
+    content::{render_markdown, RenderedContent},
+    AppError,
+};
+
+/// Subscriber key used in the unsubscribe link of previews and test sends.
+pub const TEST_SUBSCRIBER_KEY: &str = "test-recipient";
+/// Most recipients a single test send may name, so the endpoint cannot be used to blast mail.
+pub const MAX_TEST_RECIPIENTS: usize = 5;
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct IssueData {
+    pub title: String,
+    pub markdown: String,
+}
+#[derive(Deserialize, Debug, Clone, Default)]
+pub struct TestSendData {
+    /// Overrides the configured seed list when not empty. At most [`MAX_TEST_RECIPIENTS`],
+    /// all within `email_client.test_send_domains` when that is set.
+    #[serde(default)]
+    pub recipients: Vec<String>,
+}
+#[derive(Serialize, Debug, Clone, Default)]
+pub struct TestSendReport {
+    pub sent: Vec<String>,
+    pub failed: Vec<String>,
+}
+#[derive(Deserialize, Debug, Clone)]
+pub struct ScheduleData {
+    pub send_at: chrono::DateTime<chrono::Utc>,
+}
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum IssueStatus {
+    Draft,
+    Scheduled,
+    Sending,
+    /// Delivery was halted by an admin and can be resumed.
+    Paused,
+    Sent,
+    /// Delivery was stopped for good; remaining recipients never receive the issue.
+    Cancelled,
+}
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Issue {
+    pub id: Thing,
+    pub title: String,
+    /// The Markdown source the issue is rendered from.
+    pub markdown: String,
+    pub status: IssueStatus,
+    pub created_at: Datetime,
+    pub updated_at: Datetime,
+    /// When a scheduled issue is due to be sent.
+    pub send_at: Option<Datetime>,
+    pub published_at: Option<Datetime>,
+}
+impl Issue {
+    /// Renders the issue for a single recipient, exactly as it is delivered to them.
+    pub fn render_for(&self, unsubscribe_url: &str) -> crate::Result<RenderedContent> {
+        let markdown = format!(
+            "{}\n\n---\n\nYou are receiving this email because you subscribed to our newsletter. \
+             [Unsubscribe]({unsubscribe_url})\n",
+            self.markdown
+        );
+        render_markdown(&markdown)
+    }
+    /// Replaces the title and content of a draft.
+    pub fn edit(&mut self, data: IssueData) -> crate::Result<()> {
+        if self.status != IssueStatus::Draft {
+            return Err(AppError::IssueNotEditable);
+        }
+        validate_title(&data.title)?;
+        self.title = data.title;
+        self.markdown = data.markdown;
+        self.updated_at = Datetime(chrono::Utc::now());
+        Ok(())
+    }
+    /// Schedules a draft, or reschedules an issue that has not been sent yet.
+    pub fn schedule(&mut self, send_at: chrono::DateTime<chrono::Utc>) -> crate::Result<()> {
+        if !matches!(self.status, IssueStatus::Draft | IssueStatus::Scheduled) {
+            return Err(AppError::IssueNotEditable);
+        }
+        let now = chrono::Utc::now();
+        if send_at <= now {
+            return Err(AppError::InvalidSendTime);
+        }
+        self.status = IssueStatus::Scheduled;
+        self.send_at = Some(Datetime(send_at));
+        self.updated_at = Datetime(now);
+        Ok(())
+    }
+    /// Marks a draft, or an issue that is still scheduled, as due right away.
+    pub fn send_now(&mut self) -> crate::Result<()> {
+        if !matches!(self.status, IssueStatus::Draft | IssueStatus::Scheduled) {
+            return Err(AppError::IssueNotEditable);
+        }
+        let now = chrono::Utc::now();
+        self.status = IssueStatus::Scheduled;
+        self.send_at = Some(Datetime(now));
+        self.updated_at = Datetime(now);
+        Ok(())
+    }
+    /// Halts an in-progress delivery.
+    pub fn pause(&mut self) -> crate::Result<()> {
+        if self.status != IssueStatus::Sending {
+            return Err(AppError::IssueNotEditable);
+        }
+        self.status = IssueStatus::Paused;
+        self.updated_at = Datetime(chrono::Utc::now());
+        Ok(())
+    }
+    /// Continues a paused delivery with the recipients that have not been sent to yet.
+    pub fn resume(&mut self) -> crate::Result<()> {
+        if self.status != IssueStatus::Paused {
+            return Err(AppError::IssueNotEditable);
+        }
+        self.status = IssueStatus::Sending;
+        self.updated_at = Datetime(chrono::Utc::now());
+        Ok(())
+    }
+    /// Cancels a pending schedule, turning the issue back into a draft.
+    pub fn unschedule(&mut self) -> crate::Result<()> {
+        if self.status != IssueStatus::Scheduled {
+            return Err(AppError::IssueNotEditable);
+        }
+        self.status = IssueStatus::Draft;
+        self.send_at = None;
+        self.updated_at = Datetime(chrono::Utc::now());
+        Ok(())
+    }
+}
+impl TryFrom<IssueData> for Issue {
+    type Error = AppError;
+    fn try_from(value: IssueData) -> Result<Self, Self::Error> {
+        validate_title(&value.title)?;
+        let now = Datetime(chrono::Utc::now());
+        Ok(Self {
+            id: Thing {
+                tb: String::from("issue"),
+                id: Id::uuid(),
+            },
+            title: value.title,
+            markdown: value.markdown,
+            status: IssueStatus::Draft,
+            created_at: now.clone(),
+            updated_at: now,
+            send_at: None,
+            published_at: None,
+        })
+    }
+}
+fn validate_title(title: &str) -> crate::Result<()> {
+    if title.trim().is_empty() {
+        Err(AppError::InvalidTitle)
+    } else {
+        Ok(())
+    }
+}
@@ -1 +1,3 @@
+pub mod delivery;
+pub mod issue;
 pub mod subscriber;
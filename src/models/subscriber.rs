@@ -1,19 +1,54 @@
 use serde::{Deserialize, Serialize};
 use surrealdb::sql::{Id, Thing};
 
-use crate::{validate_name, AppError};
+use crate::{redaction::Redacted, validate_name, AppError};
 
-#[derive(Serialize, Deserialize, Debug, Clone)]
+#[derive(Serialize, Deserialize, Clone)]
 pub struct FormData {
     pub email: String,
     pub name: String,
 }
-#[derive(Serialize, Deserialize, Debug, Clone)]
+impl std::fmt::Debug for FormData {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("FormData")
+            .field("email", &Redacted(&self.email))
+            .field("name", &Redacted(&self.name))
+            .finish()
+    }
+}
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum SubscriberStatus {
+    /// Signed up, but has not followed the confirmation link yet; receives no issues.
+    PendingConfirmation,
+    Confirmed,
+}
+impl SubscriberStatus {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            SubscriberStatus::PendingConfirmation => "pending_confirmation",
+            SubscriberStatus::Confirmed => "confirmed",
+        }
+    }
+}
+#[derive(Serialize, Deserialize, Clone)]
 pub struct Subscriber {
     pub id: Thing,
     pub email: String,
     pub username: String,
     pub subscribed_at: surrealdb::sql::Datetime,
+    pub status: SubscriberStatus,
+}
+impl std::fmt::Debug for Subscriber {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Subscriber")
+            .field("id", &self.id)
+            .field("email", &Redacted(&self.email))
+            .field("username", &Redacted(&self.username))
+            .field("subscribed_at", &self.subscribed_at)
+            .field("status", &self.status)
+            .finish()
+    }
 }
 impl TryFrom<FormData> for Subscriber {
     type Error = AppError;
@@ -29,6 +64,7 @@ impl TryFrom<FormData> for Subscriber {
                 email: value.email,
                 username: value.name,
                 subscribed_at: surrealdb::sql::Datetime(chrono::Utc::now()),
+                status: SubscriberStatus::PendingConfirmation,
             })
         }
     }
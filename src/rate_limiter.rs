@@ -0,0 +1,184 @@
+use std::{sync::Mutex, time::Duration};
+
+use tokio::time::Instant;
+
+use crate::{configuration::RateLimitSettings, Storage};
+
+/// How often the effective send rate is reported.
+const REPORT_WINDOW: Duration = Duration::from_secs(10);
+/// The bucket in the database that every sending process draws from.
+const SHARED_BUCKET: &str = "email";
+
+/// A token bucket shared by every clone of the email client.
+///
+/// With storage attached, the bucket lives in the database and is shared by every process
+/// using it; the in-process bucket only takes over while the database cannot be reached.
+#[derive(Debug)]
+pub struct RateLimiter {
+    rate: f64,
+    burst: f64,
+    state: Mutex<BucketState>,
+    shared: Option<Storage>,
+}
+#[derive(Debug)]
+struct BucketState {
+    tokens: f64,
+    last_refill: Instant,
+    blocked_until: Option<Instant>,
+    window_start: Instant,
+    sent_in_window: u32,
+}
+impl RateLimiter {
+    pub fn new(settings: &RateLimitSettings) -> Self {
+        let now = Instant::now();
+        let burst = f64::from(settings.burst.max(1));
+        Self {
+            rate: settings.messages_per_second.max(f64::MIN_POSITIVE),
+            burst,
+            state: Mutex::new(BucketState {
+                tokens: burst,
+                last_refill: now,
+                blocked_until: None,
+                window_start: now,
+                sent_in_window: 0,
+            }),
+            shared: None,
+        }
+    }
+    /// A limiter drawing from the bucket every process using `storage` shares.
+    pub fn shared(settings: &RateLimitSettings, storage: Storage) -> Self {
+        Self {
+            shared: Some(storage),
+            ..Self::new(settings)
+        }
+    }
+    /// Waits until a message may be sent and takes a token for it.
+    pub async fn acquire(&self) {
+        if let Some(storage) = &self.shared {
+            loop {
+                match storage
+                    .take_send_token(SHARED_BUCKET, self.rate, self.burst)
+                    .await
+                {
+                    Ok(None) => {
+                        let mut state = self.state.lock().expect("rate limiter lock poisoned");
+                        self.record_send(&mut state, Instant::now());
+                        return;
+                    }
+                    Ok(Some(wait)) => tokio::time::sleep(wait).await,
+                    Err(e) => {
+                        tracing::warn!(error = ?e, "Shared send bucket unavailable, limiting this process only");
+                        break;
+                    }
+                }
+            }
+        }
+        loop {
+            let wait = {
+                let mut state = self.state.lock().expect("rate limiter lock poisoned");
+                let now = Instant::now();
+                self.refill(&mut state, now);
+                match state.blocked_until {
+                    Some(until) if until > now => until - now,
+                    _ if state.tokens >= 1.0 => {
+                        state.tokens -= 1.0;
+                        self.record_send(&mut state, now);
+                        return;
+                    }
+                    _ => Duration::from_secs_f64((1.0 - state.tokens) / self.rate),
+                }
+            };
+            tokio::time::sleep(wait).await;
+        }
+    }
+    /// Stops all sends for `delay`, e.g. after the provider answered with HTTP 429.
+    pub async fn back_off(&self, delay: Duration) {
+        {
+            let mut state = self.state.lock().expect("rate limiter lock poisoned");
+            let until = Instant::now() + delay;
+            state.blocked_until = Some(state.blocked_until.map_or(until, |b| b.max(until)));
+            state.tokens = 0.0;
+        }
+        if let Some(storage) = &self.shared {
+            if let Err(e) = storage.block_send_bucket(SHARED_BUCKET, delay).await {
+                tracing::warn!(error = ?e, "Failed to hold back the other senders");
+            }
+        }
+    }
+    fn refill(&self, state: &mut BucketState, now: Instant) {
+        let elapsed = now.duration_since(state.last_refill).as_secs_f64();
+        state.tokens = (state.tokens + elapsed * self.rate).min(self.burst);
+        state.last_refill = now;
+    }
+    fn record_send(&self, state: &mut BucketState, now: Instant) {
+        state.sent_in_window += 1;
+        let elapsed = now.duration_since(state.window_start);
+        if elapsed >= REPORT_WINDOW {
+            let effective_rate = f64::from(state.sent_in_window) / elapsed.as_secs_f64();
+            tracing::info!(
+                effective_rate,
+                configured_rate = self.rate,
+                "Email send rate"
+            );
+            state.window_start = now;
+            state.sent_in_window = 0;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use tokio::time::Instant;
+
+    use crate::{configuration::RateLimitSettings, rate_limiter::RateLimiter, Storage};
+
+    fn settings(messages_per_second: f64, burst: u32) -> RateLimitSettings {
+        RateLimitSettings {
+            messages_per_second,
+            burst,
+            max_retries: 0,
+        }
+    }
+    fn limiter(messages_per_second: f64, burst: u32) -> RateLimiter {
+        RateLimiter::new(&settings(messages_per_second, burst))
+    }
+    #[tokio::test(start_paused = true)]
+    async fn burst_is_sent_without_waiting() {
+        let limiter = limiter(1.0, 5);
+        let start = Instant::now();
+        for _ in 0..5 {
+            limiter.acquire().await;
+        }
+        assert!(start.elapsed() < Duration::from_millis(1));
+    }
+    #[tokio::test(start_paused = true)]
+    async fn sends_beyond_the_burst_are_throttled() {
+        let limiter = limiter(2.0, 1);
+        let start = Instant::now();
+        for _ in 0..5 {
+            limiter.acquire().await;
+        }
+        assert!(start.elapsed() >= Duration::from_secs(2));
+        assert!(start.elapsed() < Duration::from_millis(2100));
+    }
+    #[tokio::test(start_paused = true)]
+    async fn back_off_blocks_every_sender() {
+        let limiter = limiter(100.0, 10);
+        limiter.back_off(Duration::from_secs(3)).await;
+        let start = Instant::now();
+        limiter.acquire().await;
+        assert!(start.elapsed() >= Duration::from_secs(3));
+    }
+    #[tokio::test(start_paused = true)]
+    async fn an_unreachable_shared_bucket_falls_back_to_the_local_one() {
+        let limiter = RateLimiter::shared(&settings(2.0, 1), Storage::unreachable());
+        let start = Instant::now();
+        for _ in 0..3 {
+            limiter.acquire().await;
+        }
+        assert!(start.elapsed() >= Duration::from_secs(1));
+        assert!(start.elapsed() < Duration::from_millis(1100));
+    }
+}
@@ -0,0 +1,83 @@
+use std::{
+    borrow::Cow,
+    fmt,
+    sync::atomic::{AtomicBool, Ordering},
+};
+
+/// Whether personal data is written to logs unmasked. Only ever enabled for local debugging.
+static LOG_PII: AtomicBool = AtomicBool::new(false);
+
+/// Turns unmasked logging of personal data on or off for the whole process.
+pub fn set_log_pii(enabled: bool) {
+    LOG_PII.store(enabled, Ordering::Relaxed);
+}
+
+/// Wraps personal data, such as an email address or a name, so it is masked when logged.
+///
+/// Emails keep their first character and domain (`j***@example.com`), anything else keeps its
+/// first character only. The value is shown as is when [`set_log_pii`] has been enabled.
+#[derive(Clone, Copy)]
+pub struct Redacted<T>(pub T);
+impl<T: AsRef<str>> fmt::Display for Redacted<T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&render(self.0.as_ref(), LOG_PII.load(Ordering::Relaxed)))
+    }
+}
+impl<T: AsRef<str>> fmt::Debug for Redacted<T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "\"{self}\"")
+    }
+}
+
+/// The value as logged: unmasked only when `log_pii` is set.
+fn render(value: &str, log_pii: bool) -> Cow<'_, str> {
+    match log_pii {
+        true => Cow::Borrowed(value),
+        false => Cow::Owned(mask(value)),
+    }
+}
+
+fn mask(value: &str) -> String {
+    let (local, domain) = match value.rsplit_once('@') {
+        Some((local, domain)) => (local, Some(domain)),
+        None => (value, None),
+    };
+    let mut masked: String = local.chars().take(1).collect();
+    masked.push_str("***");
+    if let Some(domain) = domain {
+        masked.push('@');
+        masked.push_str(domain);
+    }
+    masked
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn emails_keep_their_first_letter_and_domain() {
+        assert_eq!(mask("ursula@example.com"), "u***@example.com");
+    }
+
+    #[test]
+    fn names_keep_their_first_letter() {
+        assert_eq!(mask("Ursula Le Guin"), "U***");
+        assert_eq!(mask("Ævar"), "Æ***");
+        assert_eq!(mask(""), "***");
+    }
+
+    #[test]
+    fn values_are_only_shown_once_pii_logging_is_enabled() {
+        assert_eq!(render("ursula@example.com", false), "u***@example.com");
+        assert_eq!(render("ursula@example.com", true), "ursula@example.com");
+    }
+
+    // Leaves the process-wide flag alone, as other tests log `Redacted` values concurrently.
+    #[test]
+    fn redacted_values_are_masked_by_default() {
+        let email = Redacted("ursula@example.com");
+        assert_eq!(email.to_string(), "u***@example.com");
+        assert_eq!(format!("{email:?}"), "\"u***@example.com\"");
+    }
+}
@@ -0,0 +1,82 @@
+use axum::{
+    extract::Request,
+    http::{HeaderName, HeaderValue},
+    middleware::Next,
+    response::Response,
+};
+use tower_http::request_id::RequestId;
+
+pub static REQUEST_ID_HEADER: HeaderName = HeaderName::from_static("x-request-id");
+/// Longest inbound request id we are willing to carry into logs and error bodies.
+const MAX_LEN: usize = 128;
+
+tokio::task_local! {
+    static CURRENT: String;
+}
+
+/// The id of the request being handled by the current task, if any.
+pub fn current() -> Option<String> {
+    CURRENT.try_with(Clone::clone).ok()
+}
+
+/// Whether an inbound id is safe to reuse: short and made of URL-safe characters only.
+fn is_well_formed(id: &str) -> bool {
+    !id.is_empty()
+        && id.len() <= MAX_LEN
+        && id
+            .bytes()
+            .all(|b| b.is_ascii_alphanumeric() || matches!(b, b'-' | b'_' | b'.' | b':'))
+}
+
+/// Assigns every request an id and echoes it back in the response.
+///
+/// A well-formed inbound `x-request-id` is kept so ids can be followed across services;
+/// anything else is replaced by a fresh UUID. The id is also stored in the request extensions
+/// and made available to the rest of the task through [`current`].
+pub async fn assign_request_id(mut request: Request, next: Next) -> Response {
+    let id = request
+        .headers()
+        .get(&REQUEST_ID_HEADER)
+        .and_then(|value| value.to_str().ok())
+        .filter(|id| is_well_formed(id))
+        .map_or_else(|| uuid::Uuid::new_v4().to_string(), str::to_string);
+    let value = HeaderValue::from_str(&id).expect("request ids are always valid header values");
+    request
+        .headers_mut()
+        .insert(REQUEST_ID_HEADER.clone(), value.clone());
+    request
+        .extensions_mut()
+        .insert(RequestId::new(value.clone()));
+    let mut response = CURRENT.scope(id, next.run(request)).await;
+    response
+        .headers_mut()
+        .insert(REQUEST_ID_HEADER.clone(), value);
+    response
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn uuids_and_trace_style_ids_are_well_formed() {
+        assert!(is_well_formed("0b9c1e5e-8d0e-4a0f-9f7e-3c1f2a6d7b8e"));
+        assert!(is_well_formed("01HZX3J5K2M9Q7R8S0T1V2W3X4"));
+        assert!(is_well_formed("lb-1:req.42_a"));
+    }
+
+    #[test]
+    fn empty_long_or_unusual_ids_are_rejected() {
+        assert!(!is_well_formed(""));
+        assert!(!is_well_formed(&"a".repeat(MAX_LEN + 1)));
+        assert!(!is_well_formed("has space"));
+        assert!(!is_well_formed("<script>"));
+    }
+
+    #[tokio::test]
+    async fn current_is_only_set_inside_the_request_scope() {
+        assert_eq!(current(), None);
+        let inside = CURRENT.scope("abc".to_string(), async { current() }).await;
+        assert_eq!(inside.as_deref(), Some("abc"));
+    }
+}
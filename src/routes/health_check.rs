@@ -1,4 +1,152 @@
-use axum::{http::StatusCode, response::IntoResponse};
-pub async fn health_check() -> impl IntoResponse {
-    StatusCode::OK
+use std::{future::Future, sync::Arc, time::Duration};
+
+use axum::{http::StatusCode, Extension, Json};
+use serde::Serialize;
+
+use crate::{circuit_breaker::CircuitState, email_client::ProviderStatus, EmailClient, Storage};
+
+/// How long a single dependency may take to answer the readiness probe.
+const READINESS_TIMEOUT: Duration = Duration::from_secs(2);
+
+#[derive(Serialize, Debug)]
+pub struct HealthReport {
+    pub status: &'static str,
+    pub email_providers: Vec<ProviderStatus>,
+}
+pub async fn health_check(
+    Extension(email_client): Extension<Arc<EmailClient>>,
+) -> Json<HealthReport> {
+    Json(HealthReport {
+        status: "ok",
+        email_providers: email_client.provider_statuses(),
+    })
+}
+
+#[derive(Serialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum DependencyStatus {
+    Up,
+    Down,
+}
+/// The outcome of checking a single dependency.
+#[derive(Serialize, Debug)]
+pub struct DependencyCheck {
+    pub status: DependencyStatus,
+    /// Whether the instance should stop receiving traffic while this dependency is down.
+    pub critical: bool,
+    pub latency_ms: f64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+}
+#[derive(Serialize, Debug)]
+pub struct Dependencies {
+    pub database: DependencyCheck,
+    pub email: DependencyCheck,
+}
+#[derive(Serialize, Debug)]
+pub struct ReadinessReport {
+    pub status: &'static str,
+    pub dependencies: Dependencies,
+    pub email_providers: Vec<ProviderStatus>,
+}
+/// Reports whether the instance can serve traffic.
+///
+/// The database is critical: when it cannot be reached the probe answers 503. Email only
+/// degrades delivery, which the queue retries later, so it is reported but never fails the probe.
+pub async fn health_ready(
+    Extension(storage): Extension<Arc<Storage>>,
+    Extension(email_client): Extension<Arc<EmailClient>>,
+) -> (StatusCode, Json<ReadinessReport>) {
+    let database = check(true, storage.ping()).await;
+    let email_providers = email_client.provider_statuses();
+    let email = check(false, async {
+        if email_providers
+            .iter()
+            .all(|p| p.circuit == CircuitState::Open)
+        {
+            Err("every email provider circuit is open".to_string())
+        } else {
+            Ok(())
+        }
+    })
+    .await;
+    let ready = [&database, &email]
+        .iter()
+        .all(|c| !c.critical || c.status == DependencyStatus::Up);
+    let (code, status) = if ready {
+        (StatusCode::OK, "ready")
+    } else {
+        (StatusCode::SERVICE_UNAVAILABLE, "unavailable")
+    };
+    let report = ReadinessReport {
+        status,
+        dependencies: Dependencies { database, email },
+        email_providers,
+    };
+    (code, Json(report))
+}
+
+async fn check<E: std::fmt::Debug>(
+    critical: bool,
+    probe: impl Future<Output = std::result::Result<(), E>>,
+) -> DependencyCheck {
+    let start = std::time::Instant::now();
+    let error = match tokio::time::timeout(READINESS_TIMEOUT, probe).await {
+        Ok(Ok(())) => None,
+        Ok(Err(e)) => Some(format!("{e:?}")),
+        Err(_) => Some(format!("no answer within {READINESS_TIMEOUT:?}")),
+    };
+    DependencyCheck {
+        status: if error.is_none() {
+            DependencyStatus::Up
+        } else {
+            DependencyStatus::Down
+        },
+        critical,
+        latency_ms: start.elapsed().as_secs_f64() * 1000.0,
+        error,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use secrecy::Secret;
+
+    use super::*;
+    use crate::configuration::EmailClientSettings;
+
+    fn email_client() -> EmailClient {
+        EmailClient::new(&EmailClientSettings {
+            token: Secret::new("token".into()),
+            base_url: "http://localhost/".into(),
+            sender: "mail@example.com".into(),
+            seed_list: Vec::new(),
+            test_send_domains: Vec::new(),
+            rate_limit: Default::default(),
+            circuit_breaker: Default::default(),
+            secondary: None,
+            timeouts: Default::default(),
+        })
+        .unwrap()
+    }
+
+    #[tokio::test]
+    async fn readiness_fails_with_a_503_when_the_database_is_down() {
+        let (status, Json(report)) = health_ready(
+            Extension(Arc::new(Storage::unreachable())),
+            Extension(Arc::new(email_client())),
+        )
+        .await;
+
+        assert_eq!(status, StatusCode::SERVICE_UNAVAILABLE);
+        let report = serde_json::to_value(&report).unwrap();
+        assert_eq!(report["status"], "unavailable");
+        let database = &report["dependencies"]["database"];
+        assert_eq!(database["status"], "down");
+        assert_eq!(database["critical"], true);
+        assert!(database["error"].is_string());
+        // Email is still up and does not change the outcome either way.
+        assert_eq!(report["dependencies"]["email"]["status"], "up");
+        assert_eq!(report["email_providers"][0]["circuit"], "closed");
+    }
 }
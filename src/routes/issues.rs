@@ -0,0 +1,209 @@
+use std::sync::Arc;
+
+use crate::{
+    delivery::{DeliveryReport, RequeueReport},
+    issue::{
+        Issue, IssueData, IssueStatus, ScheduleData, TestSendData, TestSendReport,
+        MAX_TEST_RECIPIENTS, TEST_SUBSCRIBER_KEY,
+    },
+    startup::ApplicationBaseUrl,
+    validator::validate_email,
+    AppError, EmailClient, Result, Storage,
+};
+use axum::{extract::Path, http::StatusCode, response::Html, Extension, Json};
+#[tracing::instrument(
+    name = "Creating newsletter issue draft",
+    skip(storage, input),
+    fields(issue_title = %input.title)
+)]
+pub async fn create_issue(
+    Extension(storage): Extension<Arc<Storage>>,
+    Json(input): Json<IssueData>,
+) -> Result<Json<Issue>> {
+    let issue = Issue::try_from(input)?;
+    let issue = storage.add_issue(issue).await?;
+    Ok(Json(issue))
+}
+#[tracing::instrument(name = "Listing newsletter issues", skip(storage))]
+pub async fn list_issues(Extension(storage): Extension<Arc<Storage>>) -> Result<Json<Vec<Issue>>> {
+    let issues = storage.get_issues().await?;
+    Ok(Json(issues))
+}
+#[tracing::instrument(name = "Retrieving newsletter issue", skip(storage))]
+pub async fn get_issue(
+    Extension(storage): Extension<Arc<Storage>>,
+    Path(id): Path<String>,
+) -> Result<Json<Issue>> {
+    let issue = storage.get_issue(&id).await?;
+    Ok(Json(issue))
+}
+#[tracing::instrument(name = "Editing newsletter issue draft", skip(storage, input))]
+pub async fn update_issue(
+    Extension(storage): Extension<Arc<Storage>>,
+    Path(id): Path<String>,
+    Json(input): Json<IssueData>,
+) -> Result<Json<Issue>> {
+    let mut issue = storage.get_issue(&id).await?;
+    let current = issue.status;
+    issue.edit(input)?;
+    let issue = storage.update_issue(issue, current).await?;
+    Ok(Json(issue))
+}
+#[tracing::instrument(name = "Scheduling newsletter issue", skip(storage))]
+pub async fn schedule_issue(
+    Extension(storage): Extension<Arc<Storage>>,
+    Path(id): Path<String>,
+    Json(input): Json<ScheduleData>,
+) -> Result<Json<Issue>> {
+    let mut issue = storage.get_issue(&id).await?;
+    let current = issue.status;
+    issue.schedule(input.send_at)?;
+    let issue = storage.update_issue(issue, current).await?;
+    Ok(Json(issue))
+}
+#[tracing::instrument(name = "Pausing newsletter issue delivery", skip(storage))]
+pub async fn pause_issue(
+    Extension(storage): Extension<Arc<Storage>>,
+    Path(id): Path<String>,
+) -> Result<Json<Issue>> {
+    let mut issue = storage.get_issue(&id).await?;
+    let current = issue.status;
+    issue.pause()?;
+    let issue = storage.update_issue(issue, current).await?;
+    Ok(Json(issue))
+}
+#[tracing::instrument(name = "Resuming newsletter issue delivery", skip(storage))]
+pub async fn resume_issue(
+    Extension(storage): Extension<Arc<Storage>>,
+    Path(id): Path<String>,
+) -> Result<Json<Issue>> {
+    let mut issue = storage.get_issue(&id).await?;
+    let current = issue.status;
+    issue.resume()?;
+    let issue = storage.update_issue(issue, current).await?;
+    storage.complete_issue_if_delivered(&issue.id).await?;
+    let issue = storage.get_issue(&id).await?;
+    Ok(Json(issue))
+}
+#[tracing::instrument(name = "Cancelling newsletter issue delivery", skip(storage))]
+pub async fn cancel_issue(
+    Extension(storage): Extension<Arc<Storage>>,
+    Path(id): Path<String>,
+) -> Result<Json<Issue>> {
+    let issue = storage.get_issue(&id).await?;
+    if !storage.cancel_issue(&issue.id).await? {
+        return Err(AppError::IssueNotEditable);
+    }
+    let issue = storage.get_issue(&id).await?;
+    Ok(Json(issue))
+}
+#[tracing::instrument(name = "Cancelling newsletter issue schedule", skip(storage))]
+pub async fn unschedule_issue(
+    Extension(storage): Extension<Arc<Storage>>,
+    Path(id): Path<String>,
+) -> Result<Json<Issue>> {
+    let mut issue = storage.get_issue(&id).await?;
+    let current = issue.status;
+    issue.unschedule()?;
+    let issue = storage.update_issue(issue, current).await?;
+    Ok(Json(issue))
+}
+#[tracing::instrument(name = "Deleting newsletter issue draft", skip(storage))]
+pub async fn delete_issue(
+    Extension(storage): Extension<Arc<Storage>>,
+    Path(id): Path<String>,
+) -> Result<StatusCode> {
+    let issue = storage.get_issue(&id).await?;
+    if issue.status != IssueStatus::Draft {
+        return Err(AppError::IssueNotEditable);
+    }
+    storage.delete_issue(&id).await?;
+    Ok(StatusCode::NO_CONTENT)
+}
+#[tracing::instrument(name = "Previewing newsletter issue", skip(storage, base_url))]
+pub async fn preview_issue(
+    Extension(storage): Extension<Arc<Storage>>,
+    Extension(base_url): Extension<Arc<ApplicationBaseUrl>>,
+    Path(id): Path<String>,
+) -> Result<Html<String>> {
+    let issue = storage.get_issue(&id).await?;
+    let unsubscribe_url = base_url.unsubscribe_url(TEST_SUBSCRIBER_KEY)?;
+    let content = issue.render_for(&unsubscribe_url)?;
+    Ok(Html(content.html))
+}
+#[tracing::instrument(
+    name = "Test-sending newsletter issue",
+    skip(storage, email_client, base_url, input)
+)]
+pub async fn test_send_issue(
+    Extension(storage): Extension<Arc<Storage>>,
+    Extension(email_client): Extension<Arc<EmailClient>>,
+    Extension(base_url): Extension<Arc<ApplicationBaseUrl>>,
+    Path(id): Path<String>,
+    input: Option<Json<TestSendData>>,
+) -> Result<Json<TestSendReport>> {
+    let issue = storage.get_issue(&id).await?;
+    let Json(input) = input.unwrap_or_default();
+    if input.recipients.len() > MAX_TEST_RECIPIENTS {
+        return Err(AppError::TooManyRecipients(MAX_TEST_RECIPIENTS));
+    }
+    // Rejected here rather than by the provider, where each would use up a send.
+    if let Some(recipient) = input
+        .recipients
+        .iter()
+        .find(|r| !validate_email(r) || !email_client.accepts_test_recipient(r))
+    {
+        return Err(AppError::InvalidRecipient(recipient.clone()));
+    }
+    let recipients = if input.recipients.is_empty() {
+        email_client.seed_list().to_vec()
+    } else {
+        input.recipients
+    };
+    if recipients.is_empty() {
+        return Err(AppError::NoRecipients);
+    }
+    let unsubscribe_url = base_url.unsubscribe_url(TEST_SUBSCRIBER_KEY)?;
+    let content = issue.render_for(&unsubscribe_url)?;
+    let mut report = TestSendReport::default();
+    for recipient in recipients {
+        match email_client
+            .send_email(
+                recipient.clone(),
+                &issue.title,
+                &content.html,
+                Some(&content.text),
+            )
+            .await
+        {
+            Ok(_) => report.sent.push(recipient),
+            Err(e) => {
+                tracing::error!(error = ?e, "Failed to test-send issue");
+                report.failed.push(recipient);
+            }
+        }
+    }
+    Ok(Json(report))
+}
+#[tracing::instrument(name = "Reporting newsletter issue deliveries", skip(storage))]
+pub async fn delivery_report(
+    Extension(storage): Extension<Arc<Storage>>,
+    Path(id): Path<String>,
+) -> Result<Json<DeliveryReport>> {
+    let issue = storage.get_issue(&id).await?;
+    let counts = storage.get_delivery_counts(&issue.id).await?;
+    let failures = storage.get_failed_deliveries(&issue.id).await?;
+    Ok(Json(DeliveryReport { counts, failures }))
+}
+#[tracing::instrument(name = "Requeueing failed deliveries", skip(storage))]
+pub async fn requeue_deliveries(
+    Extension(storage): Extension<Arc<Storage>>,
+    Path(id): Path<String>,
+) -> Result<Json<RequeueReport>> {
+    let issue = storage.get_issue(&id).await?;
+    if issue.status == IssueStatus::Cancelled {
+        return Err(AppError::IssueNotEditable);
+    }
+    let requeued = storage.requeue_failed_deliveries(&issue.id).await?;
+    Ok(Json(RequeueReport { requeued }))
+}
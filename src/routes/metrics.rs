@@ -0,0 +1,40 @@
+use std::{sync::Arc, time::Instant};
+
+use axum::{
+    extract::{MatchedPath, Request},
+    middleware::Next,
+    response::Response,
+    Extension,
+};
+use metrics_exporter_prometheus::PrometheusHandle;
+
+/// Renders every recorded metric in the Prometheus text format.
+pub async fn metrics(Extension(handle): Extension<Arc<PrometheusHandle>>) -> String {
+    handle.run_upkeep();
+    handle.render()
+}
+
+/// Counts requests and records their latency, labelled by route, method and status.
+///
+/// This is its own middleware rather than part of the `TraceLayer` because the trace layer's
+/// `on_response` hook only sees the response and the latency. The route template and the
+/// method are on the request, and using the raw URI instead would give every issue id its
+/// own time series.
+pub async fn track_metrics(request: Request, next: Next) -> Response {
+    let start = Instant::now();
+    let route = request
+        .extensions()
+        .get::<MatchedPath>()
+        .map_or_else(|| "unmatched".to_string(), |path| path.as_str().to_string());
+    let method = request.method().to_string();
+    let response = next.run(request).await;
+    let labels = [
+        ("route", route),
+        ("method", method),
+        ("status", response.status().as_u16().to_string()),
+    ];
+    metrics::counter!("http_requests_total", &labels).increment(1);
+    metrics::histogram!("http_request_duration_seconds", &labels)
+        .record(start.elapsed().as_secs_f64());
+    response
+}
@@ -1,4 +1,10 @@
 mod health_check;
+mod issues;
+mod metrics;
+mod newsletters;
 mod subscriptions;
 pub use health_check::*;
+pub use issues::*;
+pub use metrics::*;
+pub use newsletters::*;
 pub use subscriptions::*;
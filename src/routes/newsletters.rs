@@ -0,0 +1,26 @@
+use std::sync::Arc;
+
+use crate::{
+    issue::{Issue, IssueData, IssueStatus},
+    Result, Storage,
+};
+use axum::{Extension, Json};
+use surrealdb::sql::Datetime;
+#[tracing::instrument(
+    name = "Publishing newsletter issue",
+    skip(storage, input),
+    fields(issue_title = %input.title)
+)]
+pub async fn publish_newsletter(
+    Extension(storage): Extension<Arc<Storage>>,
+    Json(input): Json<IssueData>,
+) -> Result<Json<Issue>> {
+    let mut issue = Issue::try_from(input)?;
+    issue.status = IssueStatus::Scheduled;
+    issue.send_at = Some(Datetime(chrono::Utc::now()));
+    let issue = storage.add_issue(issue).await?;
+    storage.enqueue_issue(&issue.id).await?;
+    storage.complete_issue_if_delivered(&issue.id).await?;
+    let issue = storage.get_issue(&issue.id.id.to_raw()).await?;
+    Ok(Json(issue))
+}
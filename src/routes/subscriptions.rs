@@ -1,31 +1,176 @@
 use std::sync::Arc;
 
 use crate::{
-    subscriber::{FormData, Subscriber},
-    Result, Storage,
+    configuration::SubscriptionSettings,
+    redaction::Redacted,
+    startup::ApplicationBaseUrl,
+    subscriber::{FormData, Subscriber, SubscriberStatus},
+    AppError, EmailClient, Result, Storage,
 };
-use axum::{http::HeaderMap, Extension, Form, Json};
+use axum::{extract::Query, response::Html, Extension, Form};
+use serde::Deserialize;
+use surrealdb::sql::Thing;
+/// What every accepted subscription request gets back, whether the address is new, pending
+/// or already confirmed, so the response does not reveal who is on the list.
+const SUBSCRIBED: &str = "Thanks! Check your inbox to confirm your subscription.";
 #[tracing::instrument(
     name = "Adding new subscriber",
-    skip(storage, input, headers),
+    skip(storage, email_client, base_url, settings, input),
     fields(
-        request_id = %get_req_id(headers.clone()),
-        subscriber_email = %input.email,
-        subscriber_name = %input.name
+        subscriber_email = %Redacted(&input.email),
+        subscriber_name = %Redacted(&input.name)
     )
 )]
 pub async fn subscribe(
     Extension(storage): Extension<Arc<Storage>>,
-    headers: HeaderMap,
+    Extension(email_client): Extension<Arc<EmailClient>>,
+    Extension(base_url): Extension<Arc<ApplicationBaseUrl>>,
+    Extension(settings): Extension<Arc<SubscriptionSettings>>,
     Form(input): Form<FormData>,
-) -> Result<Json<Subscriber>> {
-    let subscriber = storage.add_subscriber(input).await?;
-    Ok(Json(subscriber))
-}
-fn get_req_id(headers: HeaderMap) -> u64 {
-    headers
-        .get("x-request-id")
-        .and_then(|v| v.to_str().ok())
-        .and_then(|s| s.parse::<u64>().ok())
-        .unwrap_or_default()
+) -> Result<&'static str> {
+    let email = input.email.clone();
+    let subscriber = match storage.add_subscriber(input).await {
+        Ok(subscriber) => subscriber,
+        Err(AppError::EmailAlreadyExists) => storage.get_subscriber_by_email(&email).await?,
+        Err(e) => return Err(e),
+    };
+    // Anyone can post any address here, so each address gets at most one email per cooldown.
+    if !storage
+        .claim_subscription_email(&subscriber.id, settings.resend_cooldown())
+        .await?
+    {
+        tracing::info!("Subscription email sent recently, not sending another");
+        return Ok(SUBSCRIBED);
+    }
+    match subscriber.status {
+        SubscriberStatus::PendingConfirmation => {
+            let token = storage
+                .create_confirmation_token(&subscriber.id, settings.confirmation_ttl())
+                .await?;
+            send_confirmation_email(
+                &email_client,
+                &subscriber,
+                &base_url.confirmation_url(&token)?,
+            )
+            .await?;
+        }
+        SubscriberStatus::Confirmed => {
+            tracing::info!("Subscriber is already confirmed, sending a reminder");
+            send_already_subscribed_email(&email_client, &subscriber).await?;
+        }
+    }
+    Ok(SUBSCRIBED)
+}
+#[tracing::instrument(name = "Sending confirmation email", skip_all)]
+async fn send_confirmation_email(
+    email_client: &EmailClient,
+    subscriber: &Subscriber,
+    confirmation_url: &str,
+) -> Result<()> {
+    let html = format!(
+        "Welcome to our newsletter!<br />\
+        Click <a href=\"{confirmation_url}\">here</a> to confirm your subscription."
+    );
+    let text = format!(
+        "Welcome to our newsletter!\nVisit {confirmation_url} to confirm your subscription."
+    );
+    email_client
+        .send_email(subscriber.email.clone(), "Welcome!", &html, Some(&text))
+        .await?;
+    Ok(())
+}
+/// Lets a confirmed subscriber who signed up again know that nothing needs doing.
+#[tracing::instrument(name = "Sending already subscribed email", skip_all)]
+async fn send_already_subscribed_email(
+    email_client: &EmailClient,
+    subscriber: &Subscriber,
+) -> Result<()> {
+    let text = "Someone, hopefully you, asked to subscribe this address to our newsletter.\n\
+        You are already subscribed, so there is nothing to do.";
+    email_client
+        .send_email(
+            subscriber.email.clone(),
+            "You're already subscribed",
+            &text.replace('\n', "<br />"),
+            Some(text),
+        )
+        .await?;
+    Ok(())
+}
+#[derive(Deserialize, Debug)]
+pub struct ConfirmParams {
+    pub subscription_token: String,
+}
+#[tracing::instrument(name = "Confirming subscription", skip_all)]
+pub async fn confirm(
+    Extension(storage): Extension<Arc<Storage>>,
+    Query(params): Query<ConfirmParams>,
+) -> Result<&'static str> {
+    match storage
+        .confirm_subscription_token(&params.subscription_token)
+        .await?
+    {
+        Some(subscriber) => {
+            tracing::info!(subscriber_id = %subscriber.id, "Subscription confirmed");
+            Ok("Your subscription is confirmed.")
+        }
+        None => Err(AppError::InvalidConfirmationToken),
+    }
+}
+#[derive(Deserialize, Debug)]
+pub struct UnsubscribeParams {
+    pub subscriber: String,
+    /// Issued with the link by [`ApplicationBaseUrl::unsubscribe_url`].
+    pub signature: String,
+}
+impl UnsubscribeParams {
+    fn verify(&self, base_url: &ApplicationBaseUrl) -> Result<()> {
+        match base_url.verify_unsubscribe(&self.subscriber, &self.signature) {
+            true => Ok(()),
+            false => Err(AppError::InvalidUnsubscribeLink),
+        }
+    }
+}
+/// Shows the page the unsubscribe link in emails leads to. Nothing changes until the form is
+/// submitted, so link scanners and mail prefetchers following the link unsubscribe nobody.
+#[tracing::instrument(
+    name = "Showing unsubscribe form",
+    skip_all,
+    fields(subscriber_id = %params.subscriber)
+)]
+pub async fn unsubscribe_form(
+    Extension(base_url): Extension<Arc<ApplicationBaseUrl>>,
+    Query(params): Query<UnsubscribeParams>,
+) -> Result<Html<String>> {
+    params.verify(&base_url)?;
+    Ok(Html(format!(
+        "<!DOCTYPE html>\n\
+        <html><body>\n\
+        <p>Do you want to stop receiving this newsletter?</p>\n\
+        <form method=\"post\" action=\"unsubscribe\">\n\
+        <input type=\"hidden\" name=\"subscriber\" value=\"{}\" />\n\
+        <input type=\"hidden\" name=\"signature\" value=\"{}\" />\n\
+        <button type=\"submit\">Unsubscribe</button>\n\
+        </form>\n\
+        </body></html>\n",
+        ammonia::clean_text(&params.subscriber),
+        ammonia::clean_text(&params.signature),
+    )))
+}
+#[tracing::instrument(
+    name = "Unsubscribing subscriber",
+    skip_all,
+    fields(subscriber_id = %params.subscriber)
+)]
+pub async fn unsubscribe(
+    Extension(storage): Extension<Arc<Storage>>,
+    Extension(base_url): Extension<Arc<ApplicationBaseUrl>>,
+    Form(params): Form<UnsubscribeParams>,
+) -> Result<&'static str> {
+    params.verify(&base_url)?;
+    let id = Thing::from(("subscriber", params.subscriber.as_str()));
+    if let Some(subscriber) = storage.get_subscriber(&id).await? {
+        storage.delete_subscriber(subscriber).await?;
+    }
+    Ok("You have been unsubscribed.")
 }
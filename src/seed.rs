@@ -0,0 +1,340 @@
+//! Reproducible demo data: subscribers, issues in every state and their delivery records.
+use chrono::{DateTime, Duration, Utc};
+use fake::{
+    faker::{
+        lorem::en::{Paragraphs, Sentence},
+        name::en::{FirstName, LastName},
+    },
+    Fake,
+};
+use rand::{rngs::StdRng, seq::SliceRandom, Rng, SeedableRng};
+use surrealdb::sql::{Datetime, Id, Thing};
+
+use crate::{
+    configuration::EmailClientSettings,
+    delivery::{Delivery, DeliveryStatus},
+    issue::{Issue, IssueStatus},
+    subscriber::{Subscriber, SubscriberStatus},
+    Result, Storage,
+};
+
+/// How much data to generate, and from which seed.
+#[derive(Debug, Clone, Copy)]
+pub struct SeedOptions {
+    pub subscribers: usize,
+    pub issues: usize,
+    pub seed: u64,
+}
+/// Everything [`generate`] produced, ready to be inserted.
+#[derive(Debug, Clone)]
+pub struct SeedData {
+    pub subscribers: Vec<Subscriber>,
+    pub issues: Vec<Issue>,
+    pub deliveries: Vec<Delivery>,
+}
+
+/// Issue states, weighted towards the ones a long-running newsletter accumulates.
+///
+/// `sending` is left out on purpose: a running delivery worker would start mailing the
+/// generated addresses.
+const ISSUE_STATUSES: &[IssueStatus] = &[
+    IssueStatus::Sent,
+    IssueStatus::Sent,
+    IssueStatus::Sent,
+    IssueStatus::Sent,
+    IssueStatus::Sent,
+    IssueStatus::Draft,
+    IssueStatus::Draft,
+    IssueStatus::Scheduled,
+    IssueStatus::Paused,
+    IssueStatus::Cancelled,
+];
+const DOMAINS: &[&str] = &["example.com", "example.net", "example.org"];
+
+/// Builds the data set for `options`. The same options and `now` always give the same data.
+pub fn generate(options: &SeedOptions, now: DateTime<Utc>) -> SeedData {
+    let mut rng = StdRng::seed_from_u64(options.seed);
+    let subscribers: Vec<Subscriber> = (0..options.subscribers)
+        .map(|i| fake_subscriber(&mut rng, i, now))
+        .collect();
+    let mut issues = Vec::with_capacity(options.issues);
+    let mut deliveries = Vec::new();
+    for _ in 0..options.issues {
+        let issue = fake_issue(&mut rng, now);
+        deliveries.extend(fake_deliveries(&mut rng, &issue, &subscribers));
+        issues.push(issue);
+    }
+    SeedData {
+        subscribers,
+        issues,
+        deliveries,
+    }
+}
+
+/// Whether every configured email provider runs on this machine.
+///
+/// Seeded scheduled and paused issues go out to the generated subscribers once a delivery
+/// worker picks them up, so seeding is only safe when that mail cannot reach a real provider.
+pub fn mail_stays_local(settings: &EmailClientSettings) -> bool {
+    std::iter::once(&settings.base_url)
+        .chain(settings.secondary.as_ref().map(|s| &s.base_url))
+        .all(|url| {
+            let url = reqwest::Url::parse(url).ok();
+            let host = url
+                .as_ref()
+                .and_then(|url| url.host_str())
+                .unwrap_or_default();
+            let ip = host.trim_start_matches('[').trim_end_matches(']');
+            host == "localhost"
+                || ip
+                    .parse::<std::net::IpAddr>()
+                    .is_ok_and(|ip| ip.is_loopback())
+        })
+}
+
+/// Inserts `data` in one go, subscribers and issues before the deliveries pointing at them.
+#[tracing::instrument(name = "Seeding the database", skip_all)]
+pub async fn seed(storage: &Storage, data: &SeedData) -> Result<()> {
+    storage.insert_subscribers(&data.subscribers).await?;
+    storage.insert_issues(&data.issues).await?;
+    storage.insert_deliveries(&data.deliveries).await?;
+    Ok(())
+}
+
+fn fake_subscriber(rng: &mut StdRng, index: usize, now: DateTime<Utc>) -> Subscriber {
+    let first: String = FirstName().fake_with_rng(rng);
+    let last: String = LastName().fake_with_rng(rng);
+    let local: String = format!("{first}.{last}")
+        .chars()
+        .filter(|c| c.is_ascii_alphanumeric() || *c == '.')
+        .collect();
+    // The index keeps addresses unique however often the generator repeats a name.
+    let email = format!(
+        "{}.{index}@{}",
+        local.to_lowercase(),
+        DOMAINS.choose(rng).expect("DOMAINS is not empty")
+    );
+    Subscriber {
+        id: fake_id(rng, "subscriber"),
+        email,
+        username: format!("{first} {last}"),
+        subscribed_at: Datetime(now - fake_age(rng, 365)),
+        // Roughly one in seven never clicked their confirmation link.
+        status: match rng.gen_range(0..7) {
+            0 => SubscriberStatus::PendingConfirmation,
+            _ => SubscriberStatus::Confirmed,
+        },
+    }
+}
+
+fn fake_issue(rng: &mut StdRng, now: DateTime<Utc>) -> Issue {
+    let status = *ISSUE_STATUSES
+        .choose(rng)
+        .expect("ISSUE_STATUSES is not empty");
+    let title: String = Sentence(3..8).fake_with_rng(rng);
+    let paragraphs: Vec<String> = Paragraphs(2..6).fake_with_rng(rng);
+    let created_at = now - fake_age(rng, 180);
+    let (send_at, published_at) = match status {
+        IssueStatus::Draft => (None, None),
+        IssueStatus::Scheduled => (Some(now + Duration::days(rng.gen_range(1..30))), None),
+        _ => {
+            let sent = created_at + Duration::minutes(rng.gen_range(5..60 * 24));
+            let published = (status == IssueStatus::Sent).then_some(Datetime(sent));
+            (Some(sent), published)
+        }
+    };
+    Issue {
+        id: fake_id(rng, "issue"),
+        title: title.trim_end_matches('.').to_string(),
+        markdown: paragraphs.join("\n\n"),
+        status,
+        created_at: Datetime(created_at),
+        updated_at: Datetime(published_at.as_ref().map_or(created_at, |at| at.0)),
+        send_at: send_at.map(Datetime),
+        published_at,
+    }
+}
+
+/// One delivery per confirmed subscriber who had joined by the time the issue went out.
+fn fake_deliveries(rng: &mut StdRng, issue: &Issue, subscribers: &[Subscriber]) -> Vec<Delivery> {
+    let Some(sent_at) = issue.send_at.as_ref().map(|at| at.0) else {
+        return Vec::new();
+    };
+    if issue.status == IssueStatus::Scheduled {
+        return Vec::new();
+    }
+    subscribers
+        .iter()
+        .filter(|s| s.status == SubscriberStatus::Confirmed && s.subscribed_at.0 <= sent_at)
+        .map(|s| {
+            let status = fake_delivery_status(rng, issue.status);
+            let (message_id, last_error) = match status {
+                DeliveryStatus::Sent => (Some(fake_uuid(rng).to_string()), None),
+                DeliveryStatus::Failed => (None, Some("provider answered 503".to_string())),
+                DeliveryStatus::Bounced => (None, Some("inactive recipient".to_string())),
+                _ => (None, None),
+            };
+            let updated_at = sent_at + Duration::seconds(rng.gen_range(0..3600));
+            Delivery {
+                id: fake_id(rng, "delivery"),
+                issue: issue.id.clone(),
+                subscriber: s.id.clone(),
+                status,
+                message_id,
+                last_error,
+                created_at: Datetime(sent_at),
+                updated_at: Datetime(updated_at),
+            }
+        })
+        .collect()
+}
+
+fn fake_delivery_status(rng: &mut StdRng, issue: IssueStatus) -> DeliveryStatus {
+    let roll = rng.gen_range(0..100);
+    match (issue, roll) {
+        (IssueStatus::Paused, 50..) => DeliveryStatus::Queued,
+        (IssueStatus::Cancelled, 50..) => DeliveryStatus::Cancelled,
+        (_, 0..=3) => DeliveryStatus::Failed,
+        (_, 4..=6) => DeliveryStatus::Bounced,
+        (_, 7..=9) => DeliveryStatus::Suppressed,
+        _ => DeliveryStatus::Sent,
+    }
+}
+
+fn fake_id(rng: &mut StdRng, table: &str) -> Thing {
+    Thing {
+        tb: table.to_string(),
+        id: Id::String(fake_uuid(rng).to_string()),
+    }
+}
+
+fn fake_uuid(rng: &mut StdRng) -> uuid::Uuid {
+    uuid::Builder::from_random_bytes(rng.gen()).into_uuid()
+}
+
+/// A random age of up to `max_days`, to the second.
+fn fake_age(rng: &mut StdRng, max_days: i64) -> Duration {
+    Duration::seconds(rng.gen_range(0..max_days * 24 * 3600))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashSet;
+
+    use super::*;
+    use crate::{configuration::EmailProviderSettings, validate_name, validator::validate_email};
+
+    const OPTIONS: SeedOptions = SeedOptions {
+        subscribers: 200,
+        issues: 30,
+        seed: 7,
+    };
+
+    #[test]
+    fn the_same_seed_gives_the_same_data() {
+        let now = Utc::now();
+        let a = generate(&OPTIONS, now);
+        let b = generate(&OPTIONS, now);
+        let c = generate(&SeedOptions { seed: 8, ..OPTIONS }, now);
+        let json = |data: &SeedData| {
+            serde_json::to_string(&(&data.subscribers, &data.issues, &data.deliveries)).unwrap()
+        };
+        assert_eq!(json(&a), json(&b));
+        assert_ne!(json(&a), json(&c));
+    }
+
+    #[test]
+    fn subscribers_are_valid_and_unique() {
+        let data = generate(&OPTIONS, Utc::now());
+        assert_eq!(data.subscribers.len(), OPTIONS.subscribers);
+        let emails: HashSet<_> = data.subscribers.iter().map(|s| &s.email).collect();
+        assert_eq!(emails.len(), OPTIONS.subscribers);
+        for s in &data.subscribers {
+            assert!(validate_email(&s.email), "{}", s.email);
+            assert!(validate_name(&s.username), "{}", s.username);
+        }
+    }
+
+    #[test]
+    fn some_subscribers_never_confirmed_and_receive_nothing() {
+        let data = generate(&OPTIONS, Utc::now());
+        let pending: HashSet<_> = data
+            .subscribers
+            .iter()
+            .filter(|s| s.status == SubscriberStatus::PendingConfirmation)
+            .map(|s| s.id.to_string())
+            .collect();
+        assert!(!pending.is_empty());
+        assert!(pending.len() < OPTIONS.subscribers / 2);
+        for delivery in &data.deliveries {
+            assert!(!pending.contains(&delivery.subscriber.to_string()));
+        }
+    }
+
+    #[test]
+    fn issues_come_in_mixed_states_and_only_sent_ones_are_published() {
+        let data = generate(&OPTIONS, Utc::now());
+        assert_eq!(data.issues.len(), OPTIONS.issues);
+        let statuses: HashSet<_> = data
+            .issues
+            .iter()
+            .map(|i| format!("{:?}", i.status))
+            .collect();
+        assert!(statuses.len() >= 3, "{statuses:?}");
+        assert!(!statuses.contains("Sending"));
+        for issue in &data.issues {
+            assert_eq!(
+                issue.published_at.is_some(),
+                issue.status == IssueStatus::Sent
+            );
+        }
+    }
+
+    #[test]
+    fn deliveries_only_exist_for_issues_that_went_out() {
+        let data = generate(&OPTIONS, Utc::now());
+        assert!(!data.deliveries.is_empty());
+        for delivery in &data.deliveries {
+            let issue = data.issues.iter().find(|i| i.id == delivery.issue).unwrap();
+            assert!(!matches!(
+                issue.status,
+                IssueStatus::Draft | IssueStatus::Scheduled
+            ));
+            if delivery.status == DeliveryStatus::Queued {
+                assert_eq!(issue.status, IssueStatus::Paused);
+            }
+        }
+    }
+
+    #[test]
+    fn only_local_email_providers_count_as_local() {
+        let settings = |base_url: &str, secondary: Option<&str>| EmailClientSettings {
+            token: secrecy::Secret::new("token".into()),
+            base_url: base_url.into(),
+            sender: "mail@example.com".into(),
+            seed_list: Vec::new(),
+            test_send_domains: Vec::new(),
+            rate_limit: Default::default(),
+            circuit_breaker: Default::default(),
+            secondary: secondary.map(|base_url| EmailProviderSettings {
+                token: secrecy::Secret::new("token".into()),
+                base_url: base_url.into(),
+            }),
+            timeouts: Default::default(),
+        };
+
+        assert!(mail_stays_local(&settings("http://localhost/", None)));
+        assert!(mail_stays_local(&settings(
+            "http://127.0.0.1:8025",
+            Some("http://[::1]:8026")
+        )));
+        assert!(!mail_stays_local(&settings(
+            "https://api.postmarkapp.com/",
+            None
+        )));
+        assert!(!mail_stays_local(&settings(
+            "http://localhost/",
+            Some("https://api.sendgrid.com/")
+        )));
+    }
+}
@@ -0,0 +1,232 @@
+//! Coordinates a graceful shutdown across the HTTP servers and the background workers.
+use std::{
+    future::Future,
+    sync::{Arc, Mutex, PoisonError},
+    time::Duration,
+};
+
+use tokio::{signal, task::AbortHandle};
+use tokio_util::{sync::CancellationToken, task::TaskTracker};
+
+use crate::Result;
+
+/// Hands out the cancellation token every long-running task watches, and keeps track of
+/// those tasks so shutdown can wait for them.
+#[derive(Clone, Default)]
+pub struct Shutdown {
+    token: CancellationToken,
+    tracker: TaskTracker,
+    /// Names of the tracked tasks that have not returned yet, for reporting.
+    running: Arc<Mutex<Vec<&'static str>>>,
+    /// Names of the tracked tasks that returned an error.
+    failed: Arc<Mutex<Vec<&'static str>>>,
+    /// Handles to the tracked tasks, to abort those still running at the deadline.
+    handles: Arc<Mutex<Vec<AbortHandle>>>,
+}
+impl Shutdown {
+    pub fn new() -> Self {
+        Self::default()
+    }
+    /// The token tasks watch to stop taking new work.
+    pub fn token(&self) -> CancellationToken {
+        self.token.clone()
+    }
+    /// Starts the shutdown; tasks see their token cancelled.
+    pub fn trigger(&self) {
+        self.token.cancel();
+    }
+    /// Waits until the shutdown has started.
+    pub async fn cancelled(&self) {
+        self.token.cancelled().await
+    }
+    /// Triggers the shutdown on Ctrl+C or SIGTERM.
+    pub fn listen_for_signals(&self) {
+        let token = self.token();
+        tokio::spawn(async move {
+            tokio::select! {
+                _ = shutdown_signal() => tracing::info!("Shutdown signal received"),
+                _ = token.cancelled() => {}
+            }
+            token.cancel();
+        });
+    }
+    /// Runs `task` until it returns, and makes [`Shutdown::drain`] wait for it.
+    ///
+    /// A task returning an error triggers the shutdown, since the process can no longer do
+    /// its job.
+    pub fn spawn<F>(&self, name: &'static str, task: F)
+    where
+        F: Future<Output = Result<()>> + Send + 'static,
+    {
+        let running = Running::new(name, self.running.clone());
+        let token = self.token();
+        let failed = self.failed.clone();
+        let handle = self.tracker.spawn(async move {
+            let _running = running;
+            if let Err(e) = task.await {
+                tracing::error!(task = name, error = ?e, "Background task failed, shutting down");
+                failed
+                    .lock()
+                    .unwrap_or_else(PoisonError::into_inner)
+                    .push(name);
+                token.cancel();
+            }
+        });
+        let mut handles = self.handles.lock().unwrap_or_else(PoisonError::into_inner);
+        handles.retain(|handle| !handle.is_finished());
+        handles.push(handle.abort_handle());
+    }
+    /// Triggers the shutdown and waits up to `deadline` for every tracked task to return.
+    ///
+    /// Tasks still running at the deadline are aborted, and this only returns once they are
+    /// gone, so nothing they hold (such as a lease) is still in use afterwards. Returns their
+    /// names. Fails when a task returned an error.
+    pub async fn drain(&self, deadline: Duration) -> Result<Vec<&'static str>> {
+        self.trigger();
+        self.tracker.close();
+        let unfinished = match tokio::time::timeout(deadline, self.tracker.wait()).await {
+            Ok(()) => Vec::new(),
+            Err(_) => {
+                let unfinished = self
+                    .running
+                    .lock()
+                    .unwrap_or_else(PoisonError::into_inner)
+                    .clone();
+                tracing::warn!(
+                    ?deadline,
+                    ?unfinished,
+                    "Aborting tasks still running at the shutdown deadline"
+                );
+                for handle in self
+                    .handles
+                    .lock()
+                    .unwrap_or_else(PoisonError::into_inner)
+                    .drain(..)
+                {
+                    handle.abort();
+                }
+                self.tracker.wait().await;
+                unfinished
+            }
+        };
+        let failed = self
+            .failed
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .clone();
+        if !failed.is_empty() {
+            return Err(anyhow::anyhow!("background tasks failed: {}", failed.join(", ")).into());
+        }
+        Ok(unfinished)
+    }
+}
+/// Lists a task as running for as long as it is alive, aborted or panicked tasks included.
+struct Running {
+    name: &'static str,
+    running: Arc<Mutex<Vec<&'static str>>>,
+}
+impl Running {
+    fn new(name: &'static str, running: Arc<Mutex<Vec<&'static str>>>) -> Self {
+        running
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .push(name);
+        Self { name, running }
+    }
+}
+impl Drop for Running {
+    fn drop(&mut self) {
+        let mut running = self.running.lock().unwrap_or_else(PoisonError::into_inner);
+        if let Some(index) = running.iter().position(|name| *name == self.name) {
+            running.remove(index);
+        }
+    }
+}
+
+async fn shutdown_signal() {
+    let ctrl_c = async {
+        signal::ctrl_c()
+            .await
+            .expect("failed to install Ctrl+C handler");
+    };
+
+    #[cfg(unix)]
+    let terminate = async {
+        signal::unix::signal(signal::unix::SignalKind::terminate())
+            .expect("failed to install signal handler")
+            .recv()
+            .await;
+    };
+
+    #[cfg(not(unix))]
+    let terminate = std::future::pending::<()>();
+
+    tokio::select! {
+        _ = ctrl_c => {},
+        _ = terminate => {},
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::{AtomicBool, Ordering};
+
+    use super::*;
+
+    #[tokio::test(start_paused = true)]
+    async fn drain_waits_for_tasks_to_finish_their_work() {
+        let shutdown = Shutdown::new();
+        let finished = Arc::new(AtomicBool::new(false));
+        let token = shutdown.token();
+        let done = finished.clone();
+        shutdown.spawn("worker", async move {
+            token.cancelled().await;
+            // The send in flight when the shutdown starts.
+            tokio::time::sleep(Duration::from_secs(2)).await;
+            done.store(true, Ordering::SeqCst);
+            Ok(())
+        });
+
+        let unfinished = shutdown.drain(Duration::from_secs(5)).await.unwrap();
+
+        assert!(unfinished.is_empty());
+        assert!(finished.load(Ordering::SeqCst));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn drain_aborts_what_is_left_at_the_deadline_and_names_it() {
+        let shutdown = Shutdown::new();
+        shutdown.spawn("polite", {
+            let token = shutdown.token();
+            async move {
+                token.cancelled().await;
+                Ok(())
+            }
+        });
+        let running = Arc::new(Mutex::new(Vec::new()));
+        let stubborn = Running::new("stubborn", running.clone());
+        shutdown.spawn("stubborn", async move {
+            let _stubborn = stubborn;
+            tokio::time::sleep(Duration::from_secs(60)).await;
+            Ok(())
+        });
+
+        let unfinished = shutdown.drain(Duration::from_secs(1)).await.unwrap();
+
+        assert_eq!(unfinished, ["stubborn"]);
+        // Aborted, not left to run alongside whatever happens after the drain.
+        assert!(running.lock().unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn a_failing_task_triggers_the_shutdown() {
+        let shutdown = Shutdown::new();
+        shutdown.spawn("broken", async {
+            Err(anyhow::anyhow!("listener closed").into())
+        });
+
+        shutdown.cancelled().await;
+
+        assert!(shutdown.drain(Duration::from_secs(1)).await.is_err());
+    }
+}
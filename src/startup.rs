@@ -1,101 +1,371 @@
-use std::{
-    sync::{
-        atomic::{AtomicU64, Ordering},
-        Arc,
-    },
-    time::Duration,
-};
+use std::{collections::HashMap, sync::Arc, time::Duration};
 
 use crate::{
-    routes::{health_check, subscribe},
+    authentication::{require_admin, AdminToken},
+    configuration::{RequestTimeoutSettings, Settings, SubscriptionSettings},
+    request_id::assign_request_id,
+    routes::{
+        cancel_issue, confirm, create_issue, delete_issue, delivery_report, get_issue,
+        health_check, health_ready, list_issues, metrics, pause_issue, preview_issue,
+        publish_newsletter, requeue_deliveries, resume_issue, schedule_issue, subscribe,
+        test_send_issue, track_metrics, unschedule_issue, unsubscribe, unsubscribe_form,
+        update_issue,
+    },
+    shutdown::Shutdown,
+    telemetry::{init_metrics, TraceContextMakeSpan},
+    workers::{run_cleanup, run_delivery_worker, run_scheduler},
     EmailClient, Result, Storage,
 };
 use axum::{
     body::Bytes,
-    http::header,
+    extract::{MatchedPath, Request, State},
+    http::{header, StatusCode},
+    middleware::{self, Next},
+    response::{IntoResponse, Response},
     routing::{get, post},
     Extension, Router,
 };
-use tokio::{net::TcpListener, signal};
+use hmac::{Hmac, Mac};
+use metrics_exporter_prometheus::PrometheusHandle;
+use secrecy::{ExposeSecret, Secret};
+use sha2::Sha256;
+use tokio::net::TcpListener;
+use tokio_util::sync::CancellationToken;
 use tower::ServiceBuilder;
 use tower_http::{
-    request_id::{MakeRequestId, RequestId},
-    timeout::TimeoutLayer,
-    trace::{DefaultMakeSpan, DefaultOnResponse, TraceLayer},
+    trace::{DefaultOnResponse, TraceLayer},
     LatencyUnit, ServiceBuilderExt,
 };
 
-pub fn app(storage: Storage, email_client: EmailClient) -> Router {
+/// The public URL of the application, used to build links that end up in emails, and the
+/// key that signs the links which act on a subscriber's behalf.
+#[derive(Clone, Debug)]
+pub struct ApplicationBaseUrl {
+    url: String,
+    hmac_secret: Secret<String>,
+}
+impl ApplicationBaseUrl {
+    pub fn new(url: String, hmac_secret: Secret<String>) -> Self {
+        Self { url, hmac_secret }
+    }
+    /// Builds the link a subscriber follows to leave the list, signed so that nobody can
+    /// forge it for another subscriber.
+    pub fn unsubscribe_url(&self, subscriber_key: &str) -> Result<String> {
+        let mut url = reqwest::Url::parse(&self.url)?.join("subscriptions/unsubscribe")?;
+        url.query_pairs_mut()
+            .append_pair("subscriber", subscriber_key)
+            .append_pair("signature", &hex::encode(self.sign(subscriber_key)));
+        Ok(url.to_string())
+    }
+    /// Checks that `signature` was issued by [`ApplicationBaseUrl::unsubscribe_url`] for
+    /// `subscriber_key`.
+    pub fn verify_unsubscribe(&self, subscriber_key: &str, signature: &str) -> bool {
+        let Ok(signature) = hex::decode(signature) else {
+            return false;
+        };
+        self.mac(subscriber_key).verify_slice(&signature).is_ok()
+    }
+    fn sign(&self, subscriber_key: &str) -> Vec<u8> {
+        self.mac(subscriber_key).finalize().into_bytes().to_vec()
+    }
+    fn mac(&self, subscriber_key: &str) -> Hmac<Sha256> {
+        let mut mac = Hmac::<Sha256>::new_from_slice(self.hmac_secret.expose_secret().as_bytes())
+            .expect("HMAC accepts keys of any length");
+        mac.update(b"unsubscribe:");
+        mac.update(subscriber_key.as_bytes());
+        mac
+    }
+    /// Builds the link a new subscriber follows to confirm their address.
+    pub fn confirmation_url(&self, token: &str) -> Result<String> {
+        let mut url = reqwest::Url::parse(&self.url)?.join("subscriptions/confirm")?;
+        url.query_pairs_mut()
+            .append_pair("subscription_token", token);
+        Ok(url.to_string())
+    }
+}
+/// Request time limits, resolved from [`RequestTimeoutSettings`] once at startup.
+#[derive(Debug)]
+struct RequestTimeouts {
+    default: Duration,
+    routes: HashMap<String, Duration>,
+}
+impl From<&RequestTimeoutSettings> for RequestTimeouts {
+    fn from(settings: &RequestTimeoutSettings) -> Self {
+        Self {
+            default: Duration::from_millis(settings.request_ms),
+            routes: settings
+                .routes
+                .iter()
+                .map(|(route, ms)| (route.clone(), Duration::from_millis(*ms)))
+                .collect(),
+        }
+    }
+}
+/// Answers with 408 once a request outlives the limit configured for its route.
+async fn enforce_timeout(
+    State(timeouts): State<Arc<RequestTimeouts>>,
+    request: Request,
+    next: Next,
+) -> Response {
+    let limit = request
+        .extensions()
+        .get::<MatchedPath>()
+        .and_then(|path| timeouts.routes.get(path.as_str()))
+        .copied()
+        .unwrap_or(timeouts.default);
+    match tokio::time::timeout(limit, next.run(request)).await {
+        Ok(response) => response,
+        Err(_) => {
+            tracing::warn!(?limit, "Request timed out");
+            StatusCode::REQUEST_TIMEOUT.into_response()
+        }
+    }
+}
+pub fn app(
+    storage: Storage,
+    email_client: EmailClient,
+    base_url: ApplicationBaseUrl,
+    admin_token: AdminToken,
+    timeouts: &RequestTimeoutSettings,
+    subscriptions: &SubscriptionSettings,
+) -> Router {
     let state = std::sync::Arc::new(storage);
     let mail = std::sync::Arc::new(email_client);
+    let base_url = std::sync::Arc::new(base_url);
+    let subscriptions = std::sync::Arc::new(subscriptions.clone());
     let sensitive_headers: Arc<[_]> = vec![header::AUTHORIZATION, header::COOKIE].into();
     let mw = ServiceBuilder::new()
         .sensitive_request_headers(sensitive_headers.clone())
-        .set_x_request_id(MyMakeRequestId::default())
+        .layer(middleware::from_fn(assign_request_id))
         .layer(
             TraceLayer::new_for_http()
                 .on_body_chunk(|chunk: &Bytes, latency: Duration, _: &tracing::Span| {
                     tracing::trace!(size_bytes = chunk.len(), latency = ?latency, "sending body chunk")
                 })
-                .make_span_with(DefaultMakeSpan::new().include_headers(true))
+                .make_span_with(TraceContextMakeSpan)
                 .on_response(DefaultOnResponse::new().include_headers(true).latency_unit(LatencyUnit::Micros)),
         )
-        .propagate_x_request_id()
         .sensitive_response_headers(sensitive_headers)
-        .layer(TimeoutLayer::new(Duration::from_secs(10)));
+        .layer(middleware::from_fn_with_state(
+            Arc::new(RequestTimeouts::from(timeouts)),
+            enforce_timeout,
+        ));
+
+    let admin_only = middleware::from_fn_with_state(Arc::new(admin_token), require_admin);
+    let publishing = Router::new()
+        .route("/newsletters", post(publish_newsletter))
+        .layer(admin_only.clone());
+    let admin = Router::new()
+        .route("/issues", get(list_issues).post(create_issue))
+        .route(
+            "/issues/:id",
+            get(get_issue).put(update_issue).delete(delete_issue),
+        )
+        .route("/issues/:id/preview", get(preview_issue))
+        .route("/issues/:id/test-send", post(test_send_issue))
+        .route("/issues/:id/pause", post(pause_issue))
+        .route("/issues/:id/resume", post(resume_issue))
+        .route("/issues/:id/cancel", post(cancel_issue))
+        .route("/issues/:id/deliveries", get(delivery_report))
+        .route("/issues/:id/deliveries/requeue", post(requeue_deliveries))
+        .route(
+            "/issues/:id/schedule",
+            post(schedule_issue).delete(unschedule_issue),
+        )
+        .layer(admin_only);
 
     Router::new()
         .route("/health_check", get(health_check))
+        .route("/health/ready", get(health_ready))
         .route("/subscriptions", post(subscribe))
+        .route("/subscriptions/confirm", get(confirm))
+        .route(
+            "/subscriptions/unsubscribe",
+            get(unsubscribe_form).post(unsubscribe),
+        )
+        .merge(publishing)
+        .nest("/admin", admin)
         .layer(mw)
+        .layer(middleware::from_fn(track_metrics))
         .layer(Extension(state))
         .layer(Extension(mail))
+        .layer(Extension(base_url))
+        .layer(Extension(subscriptions))
+}
+/// The router serving `/metrics`, meant to be bound to its own port.
+pub fn metrics_app(handle: PrometheusHandle) -> Router {
+    Router::new()
+        .route("/metrics", get(metrics))
+        .layer(Extension(Arc::new(handle)))
 }
-pub async fn run(listener: TcpListener, app: Router) -> Result<()> {
+/// Connects to the database and runs the halves of the application selected by
+/// `application.mode`, serving metrics in every mode, until a shutdown signal arrives.
+///
+/// On shutdown the API stops taking requests and the workers stop picking up work; both get
+/// up to `application.shutdown.drain_timeout_ms` to finish what is in flight.
+pub async fn serve(configuration: Settings) -> Result<()> {
+    let mode = configuration.application.mode;
+    let metrics_handle = init_metrics()?;
+    let listener = match mode.runs_api() {
+        true => Some(TcpListener::bind(&configuration.app_addr()).await?),
+        false => None,
+    };
+    let metrics_listener = TcpListener::bind(&configuration.metrics_addr()).await?;
+    let base_url = ApplicationBaseUrl::new(
+        configuration.application.base_url.clone(),
+        configuration.application.hmac_secret.clone(),
+    );
+    let db = Storage::init(configuration.database.clone()).await?;
+    let email_client =
+        EmailClient::with_shared_rate_limit(&configuration.email_client, db.clone())?;
+    let shutdown = Shutdown::new();
+    shutdown.listen_for_signals();
+    tokio::spawn(db.clone().watch_connection());
+    if mode.runs_workers() {
+        shutdown.spawn("scheduler", {
+            let (db, token) = (db.clone(), shutdown.token());
+            async move {
+                run_scheduler(db, token).await;
+                Ok(())
+            }
+        });
+        shutdown.spawn("delivery worker", {
+            let (db, email_client, base_url) = (db.clone(), email_client.clone(), base_url.clone());
+            let token = shutdown.token();
+            async move {
+                run_delivery_worker(db, email_client, base_url, token).await;
+                Ok(())
+            }
+        });
+        shutdown.spawn("cleanup", {
+            let (db, settings) = (db.clone(), configuration.subscriptions.clone());
+            let token = shutdown.token();
+            async move {
+                run_cleanup(db, settings, token).await;
+                Ok(())
+            }
+        });
+    }
+    shutdown.spawn(
+        "metrics server",
+        run(
+            metrics_listener,
+            metrics_app(metrics_handle),
+            shutdown.token(),
+        ),
+    );
+    if let Some(listener) = listener {
+        let app = app(
+            db.clone(),
+            email_client,
+            base_url,
+            AdminToken::new(configuration.application.admin_token.clone()),
+            &configuration.application.timeouts,
+            &configuration.subscriptions,
+        );
+        shutdown.spawn("api server", run(listener, app, shutdown.token()));
+    }
+    tracing::info!(?mode, "Started");
+    shutdown.cancelled().await;
+    let deadline = Duration::from_millis(configuration.application.shutdown.drain_timeout_ms);
+    let drained = shutdown.drain(deadline).await;
+    // Tasks aborted at the deadline have stopped but may have left leases behind; free them
+    // for the other replicas.
+    match db.release_all_leases().await {
+        Ok(0) => {}
+        Ok(released) => tracing::warn!(released, "Released leases held by unfinished tasks"),
+        Err(e) => tracing::warn!(error = ?e, "Failed to release leases"),
+    }
+    if mode.runs_workers() {
+        match db.count_queued_deliveries().await {
+            Ok(0) => {}
+            Ok(queued) => tracing::warn!(queued, "Deliveries left in the queue for the next start"),
+            Err(e) => tracing::warn!(error = ?e, "Failed to count queued deliveries"),
+        }
+    }
+    drained?;
+    tracing::info!("Shut down");
+    Ok(())
+}
+/// Serves `app` until `shutdown` is cancelled, then finishes the requests in flight.
+pub async fn run(listener: TcpListener, app: Router, shutdown: CancellationToken) -> Result<()> {
     tracing::info!("listening on {}", listener.local_addr()?);
     axum::serve(listener, app)
-        .with_graceful_shutdown(shutdown_signal())
+        .with_graceful_shutdown(shutdown.cancelled_owned())
         .await?;
     Ok(())
 }
 
-async fn shutdown_signal() {
-    let ctrl_c = async {
-        signal::ctrl_c()
-            .await
-            .expect("failed to install Ctrl+C handler");
-    };
+#[cfg(test)]
+mod tests {
+    use axum::{body::Body, extract::Path};
+    use tower::ServiceExt;
 
-    #[cfg(unix)]
-    let terminate = async {
-        signal::unix::signal(signal::unix::SignalKind::terminate())
-            .expect("failed to install signal handler")
-            .recv()
-            .await;
-    };
+    use super::*;
+
+    fn signed_with(secret: &str) -> ApplicationBaseUrl {
+        ApplicationBaseUrl::new("http://localhost:8000".into(), Secret::new(secret.into()))
+    }
 
-    #[cfg(not(unix))]
-    let terminate = std::future::pending::<()>();
+    #[test]
+    fn unsubscribe_links_only_verify_for_their_subscriber_and_key() {
+        let base_url = signed_with("signing-key");
+        let link = reqwest::Url::parse(&base_url.unsubscribe_url("ursula").unwrap()).unwrap();
+        let query: HashMap<_, _> = link.query_pairs().into_owned().collect();
 
-    tokio::select! {
-        _ = ctrl_c => {},
-        _ = terminate => {},
+        assert_eq!(query["subscriber"], "ursula");
+        assert!(base_url.verify_unsubscribe("ursula", &query["signature"]));
+        assert!(!base_url.verify_unsubscribe("someone-else", &query["signature"]));
+        assert!(!base_url.verify_unsubscribe("ursula", "not hex"));
+        assert!(!signed_with("another-key").verify_unsubscribe("ursula", &query["signature"]));
+    }
+
+    async fn sleep_for(Path(ms): Path<u64>) -> &'static str {
+        tokio::time::sleep(Duration::from_millis(ms)).await;
+        "done"
+    }
+
+    fn router() -> Router {
+        let settings = RequestTimeoutSettings {
+            request_ms: 50,
+            routes: HashMap::from([
+                ("/slow/:ms".to_string(), 500),
+                ("/admin/slow/:ms".to_string(), 500),
+            ]),
+        };
+        Router::new()
+            .route("/fast/:ms", get(sleep_for))
+            .route("/slow/:ms", get(sleep_for))
+            .nest("/admin", Router::new().route("/slow/:ms", get(sleep_for)))
+            .layer(middleware::from_fn_with_state(
+                Arc::new(RequestTimeouts::from(&settings)),
+                enforce_timeout,
+            ))
     }
-}
-#[derive(Clone, Default)]
-struct MyMakeRequestId {
-    counter: Arc<AtomicU64>,
-}
 
-impl MakeRequestId for MyMakeRequestId {
-    fn make_request_id<B>(&mut self, _request: &axum::http::Request<B>) -> Option<RequestId> {
-        let request_id = self
-            .counter
-            .fetch_add(1, Ordering::SeqCst)
-            .to_string()
-            .parse()
-            .unwrap();
+    async fn status_of(uri: &str) -> StatusCode {
+        let request = Request::builder().uri(uri).body(Body::empty()).unwrap();
+        router().oneshot(request).await.unwrap().status()
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn requests_past_the_default_limit_get_a_408() {
+        assert_eq!(status_of("/fast/10").await, StatusCode::OK);
+        assert_eq!(status_of("/fast/100").await, StatusCode::REQUEST_TIMEOUT);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn routes_with_an_override_get_their_own_limit() {
+        assert_eq!(status_of("/slow/100").await, StatusCode::OK);
+        assert_eq!(status_of("/slow/1000").await, StatusCode::REQUEST_TIMEOUT);
+    }
 
-        Some(RequestId::new(request_id))
+    #[tokio::test(start_paused = true)]
+    async fn nested_routes_are_overridden_by_their_full_path() {
+        assert_eq!(status_of("/admin/slow/100").await, StatusCode::OK);
+        assert_eq!(
+            status_of("/admin/slow/1000").await,
+            StatusCode::REQUEST_TIMEOUT
+        );
     }
 }
@@ -1,94 +1,1604 @@
+use std::{
+    sync::{Arc, PoisonError, RwLock},
+    time::Duration,
+};
+
 use crate::{
-    configuration::Settings,
+    configuration::{DatabaseConnectionSettings, DatabaseSettings},
+    delivery::{Delivery, DeliveryCounts, DeliveryOutcome, DeliveryStatus},
+    issue::{Issue, IssueStatus},
+    redaction::Redacted,
     subscriber::{FormData, Subscriber},
     AppError, Result,
 };
 use secrecy::ExposeSecret;
 use surrealdb::{
-    engine::remote::ws::{Client, Ws},
+    engine::any::Any,
+    sql::{Datetime, Thing},
     Surreal,
 };
 use tracing::debug;
 #[derive(Clone, Debug)]
 pub struct Storage {
-    db: Surreal<Client>,
+    /// The live client; swapped for a new one when the connection is re-established.
+    client: Arc<RwLock<Surreal<Any>>>,
+    settings: Arc<DatabaseSettings>,
+    /// Identifies this process as the holder of the leases it acquires.
+    owner: Arc<str>,
+}
+/// A named, expiring claim on a piece of work, held by one process at a time.
+#[derive(serde::Deserialize, Debug, Clone)]
+pub struct Lease {
+    pub owner: String,
+    pub expires_at: Datetime,
 }
+/// Applied on every (re)connection; redefining an existing table or field is harmless, and so
+/// is re-running the data migrations, which only touch rows they have not migrated yet.
+const SCHEMA: &[&str] = &[
+    "DEFINE TABLE subscriber SCHEMAFULL;",
+    "DEFINE FIELD username ON TABLE subscriber TYPE string;",
+    "DEFINE FIELD email ON TABLE subscriber TYPE string ASSERT string::is::email($value);",
+    "DEFINE FIELD subscribed_at ON TABLE subscriber TYPE datetime;",
+    "DEFINE INDEX userEmailIndex ON TABLE subscriber COLUMNS email UNIQUE;",
+    "DEFINE FIELD status ON TABLE subscriber TYPE string ASSERT $value INSIDE ['pending_confirmation', 'confirmed'];",
+    // Subscribers from before confirmation existed have been receiving issues all along.
+    "UPDATE subscriber SET status = 'confirmed' WHERE status = NONE;",
+    "DEFINE FIELD last_notified_at ON TABLE subscriber TYPE option<datetime>;",
+    "DEFINE TABLE subscription_token SCHEMAFULL;",
+    "DEFINE FIELD subscriber ON TABLE subscription_token TYPE record<subscriber>;",
+    "DEFINE FIELD expires_at ON TABLE subscription_token TYPE datetime;",
+    "DEFINE TABLE issue SCHEMAFULL;",
+    "DEFINE FIELD title ON TABLE issue TYPE string;",
+    "DEFINE FIELD markdown ON TABLE issue TYPE string;",
+    "DEFINE FIELD status ON TABLE issue TYPE string ASSERT $value INSIDE ['draft', 'scheduled', 'sending', 'paused', 'sent', 'cancelled'];",
+    "DEFINE FIELD created_at ON TABLE issue TYPE datetime;",
+    "DEFINE FIELD updated_at ON TABLE issue TYPE datetime;",
+    "DEFINE FIELD send_at ON TABLE issue TYPE option<datetime>;",
+    "DEFINE FIELD published_at ON TABLE issue TYPE option<datetime>;",
+    "DEFINE TABLE delivery SCHEMAFULL;",
+    "DEFINE FIELD issue ON TABLE delivery TYPE record<issue>;",
+    "DEFINE FIELD subscriber ON TABLE delivery TYPE record<subscriber>;",
+    "DEFINE FIELD status ON TABLE delivery TYPE string ASSERT $value INSIDE ['queued', 'sent', 'failed', 'bounced', 'suppressed', 'cancelled'];",
+    "DEFINE FIELD message_id ON TABLE delivery TYPE option<string>;",
+    "DEFINE FIELD last_error ON TABLE delivery TYPE option<string>;",
+    "DEFINE FIELD created_at ON TABLE delivery TYPE datetime;",
+    "DEFINE FIELD updated_at ON TABLE delivery TYPE datetime;",
+    "DEFINE INDEX deliveryIndex ON TABLE delivery COLUMNS issue, subscriber UNIQUE;",
+    "DEFINE TABLE lease SCHEMAFULL;",
+    "DEFINE FIELD owner ON TABLE lease TYPE string;",
+    "DEFINE FIELD expires_at ON TABLE lease TYPE datetime;",
+    "DEFINE TABLE send_bucket SCHEMAFULL;",
+    "DEFINE FIELD tokens ON TABLE send_bucket TYPE float;",
+    "DEFINE FIELD refilled_at ON TABLE send_bucket TYPE datetime;",
+    "DEFINE FIELD blocked_until ON TABLE send_bucket TYPE option<datetime>;",
+    "DEFINE FIELD granted ON TABLE send_bucket TYPE bool;",
+];
+/// How many queued deliveries are considered per claim.
+const CLAIM_BATCH_SIZE: usize = 10;
+/// How many rows a single bulk `INSERT` carries.
+const INSERT_BATCH_SIZE: usize = 500;
+/// Length of the random confirmation tokens that end up in links.
+const TOKEN_LENGTH: usize = 25;
+
 impl Storage {
-    pub async fn init(config: Settings) -> Result<Storage> {
-        let conn = config.connection_string();
-        let root = config.root();
-        let db = Surreal::new::<Ws>(conn.expose_secret()).await?;
-        db.signin(root).await?;
-        db.use_ns("zero2prod").use_db("newsletter").await?;
-        let sql = [
-            "DEFINE TABLE subscriber SCHEMAFULL;",
-            "DEFINE FIELD username ON TABLE subscriber TYPE string;",
-            "DEFINE FIELD email ON TABLE subscriber TYPE string ASSERT string::is::email($value);",
-            "DEFINE FIELD subscribed_at ON TABLE subscriber TYPE datetime;",
-            "DEFINE INDEX userEmailIndex ON TABLE subscriber COLUMNS email UNIQUE;",
-        ];
-        for q in sql {
-            let qr = db.query(q).await?;
-            debug!("Query result: {qr:?}");
+    /// Connects to the database, retrying with backoff while it is not reachable yet.
+    pub async fn init(settings: DatabaseSettings) -> Result<Storage> {
+        let policy = &settings.connection;
+        let mut backoff = Backoff::new(policy);
+        let mut attempt = 1;
+        let client = loop {
+            match connect(&settings).await {
+                Ok(client) => break client,
+                Err(e) if attempt >= policy.startup_attempts => {
+                    tracing::error!(error = ?e, attempt, "Giving up connecting to the database");
+                    return Err(e);
+                }
+                Err(e) => {
+                    let delay = backoff.next_delay();
+                    tracing::warn!(error = ?e, attempt, ?delay, "Database is not reachable yet, retrying");
+                    tokio::time::sleep(delay).await;
+                    attempt += 1;
+                }
+            }
+        };
+        tracing::info!(attempt, "Connected to the database");
+        Ok(Storage {
+            client: Arc::new(RwLock::new(client)),
+            settings: Arc::new(settings),
+            owner: uuid::Uuid::new_v4().to_string().into(),
+        })
+    }
+    fn db(&self) -> Surreal<Any> {
+        self.client
+            .read()
+            .unwrap_or_else(PoisonError::into_inner)
+            .clone()
+    }
+    /// Checks the connection periodically and replaces it when it is broken.
+    ///
+    /// Runs for as long as the process does.
+    pub async fn watch_connection(self) {
+        let policy = &self.settings.connection;
+        let interval = Duration::from_millis(policy.health_check_interval_ms);
+        loop {
+            tokio::time::sleep(interval).await;
+            let timeout = Duration::from_millis(policy.connect_timeout_ms);
+            let healthy = tokio::time::timeout(timeout, self.ping()).await;
+            if let Ok(Ok(())) = healthy {
+                continue;
+            }
+            tracing::error!("Lost the database connection, reconnecting");
+            metrics::counter!("storage_reconnects_total").increment(1);
+            self.reconnect().await;
+        }
+    }
+    /// Opens a new connection, retrying until it succeeds, and swaps it in.
+    async fn reconnect(&self) {
+        let mut backoff = Backoff::new(&self.settings.connection);
+        let mut attempt = 1;
+        loop {
+            match connect(&self.settings).await {
+                Ok(client) => {
+                    *self.client.write().unwrap_or_else(PoisonError::into_inner) = client;
+                    tracing::info!(attempt, "Reconnected to the database");
+                    return;
+                }
+                Err(e) => {
+                    let delay = backoff.next_delay();
+                    tracing::warn!(error = ?e, attempt, ?delay, "Failed to reconnect to the database");
+                    tokio::time::sleep(delay).await;
+                    attempt += 1;
+                }
+            }
         }
-        Ok(Storage { db })
+    }
+    /// Applies the schema again. Every connection already does this, so it is only needed to
+    /// upgrade a database without restarting the server.
+    #[tracing::instrument(name = "Applying the database schema", skip(self))]
+    pub async fn migrate(&self) -> Result<usize> {
+        observed("migrate", async {
+            apply_schema(&self.db()).await?;
+            Ok(SCHEMA.len())
+        })
+        .await
+    }
+    /// Checks that the database connection is alive.
+    #[tracing::instrument(name = "Pinging the database", skip(self))]
+    pub async fn ping(&self) -> Result<()> {
+        observed("ping", async {
+            self.db()
+                .health()
+                .await
+                .map_err(|_| AppError::DatabaseError)?;
+            Ok(())
+        })
+        .await
     }
     #[tracing::instrument(name = "Saving new subscriber details in the database", skip(input))]
     pub async fn add_subscriber(&self, input: FormData) -> Result<Subscriber> {
-        let s = Subscriber::try_from(input)?;
-        let subscriber: Option<Subscriber> =
-            self.db.create(s.id.clone()).content(s).await.map_err(|e| {
-                if e.to_string().contains("userEmailIndex") {
-                    AppError::EmailAlreadyExists
-                } else if e.to_string().contains("string::is::email") {
-                    AppError::InvalidEmail
-                } else {
-                    AppError::Custom(e.into())
+        observed("add_subscriber", async {
+            let s = Subscriber::try_from(input)?;
+            let subscriber: Option<Subscriber> = self
+                .db()
+                .create(s.id.clone())
+                .content(s)
+                .await
+                .map_err(|e| {
+                    if e.to_string().contains("userEmailIndex") {
+                        AppError::EmailAlreadyExists
+                    } else if e.to_string().contains("string::is::email") {
+                        AppError::InvalidEmail
+                    } else {
+                        AppError::Custom(e.into())
+                    }
+                })?;
+            match subscriber {
+                Some(s) => {
+                    tracing::debug!("New subscriber details have been saved");
+                    Ok(s)
+                }
+                None => {
+                    tracing::error!("Failed to save subscriber to db!");
+                    Err(AppError::DatabaseError)
                 }
-            })?;
-        match subscriber {
-            Some(s) => {
-                tracing::debug!("New subscriber details have been saved");
-                Ok(s)
-            }
-            None => {
-                tracing::error!("Failed to save subscriber to db!");
-                Err(AppError::DatabaseError)
             }
-        }
+        })
+        .await
     }
-    #[tracing::instrument(name = "Retrieveing subscriber by email")]
+    #[tracing::instrument(
+        name = "Retrieveing subscriber by email",
+        skip(self, email),
+        fields(email = %Redacted(email.to_string()))
+    )]
     pub async fn get_subscriber_by_email<T>(&self, email: T) -> Result<Subscriber>
     where
         T: ToString + std::fmt::Debug,
     {
-        tracing::debug!("Searching for '{}' in database.", email.to_string());
-        let sql = format!(
-            "SELECT * FROM subscriber WHERE email == '{}';",
-            email.to_string()
-        );
+        observed("get_subscriber_by_email", async {
+            tracing::debug!(
+                "Searching for '{}' in database.",
+                Redacted(email.to_string())
+            );
+            let mut res = self
+                .db()
+                .query("SELECT * FROM subscriber WHERE email = $email;")
+                .bind(("email", email.to_string()))
+                .await
+                .map_err(|_| AppError::DatabaseError)?;
+            let s: Vec<Subscriber> = res.take(0).map_err(|_| AppError::DatabaseError)?;
+            match s.first() {
+                Some(s) => Ok(s.clone()),
+                None => Err(AppError::UserNotFound),
+            }
+        })
+        .await
+    }
+    #[tracing::instrument(name = "Deleting subscriber", skip(self))]
+    pub async fn delete_subscriber(&self, subscriber: Subscriber) -> Result<()> {
+        observed("delete_subscriber", async {
+            tracing::debug!(
+                "Deleting subscriber with email: '{}' from database.",
+                Redacted(&subscriber.email)
+            );
+            self.db()
+                .query(
+                    "DELETE subscription_token WHERE subscriber = $subscriber; DELETE $subscriber;",
+                )
+                .bind(("subscriber", subscriber.id))
+                .await
+                .map_err(|_| AppError::DatabaseError)?;
+            Ok(())
+        })
+        .await
+    }
+    #[tracing::instrument(name = "Retrieving all subscribers", skip(self))]
+    pub async fn get_subscribers(&self) -> Result<Vec<Subscriber>> {
+        observed("get_subscribers", async {
+            let subscribers: Vec<Subscriber> = self
+                .db()
+                .select("subscriber")
+                .await
+                .map_err(|_| AppError::DatabaseError)?;
+            Ok(subscribers)
+        })
+        .await
+    }
+    /// Issues a new confirmation token for `subscriber`, valid for `ttl`, replacing any
+    /// token sent before so that only the latest link works.
+    #[tracing::instrument(name = "Creating confirmation token", skip(self))]
+    pub async fn create_confirmation_token(
+        &self,
+        subscriber: &Thing,
+        ttl: Duration,
+    ) -> Result<String> {
+        observed("create_confirmation_token", async {
+            let token = generate_token();
+            let sql = "
+                BEGIN TRANSACTION;
+                DELETE subscription_token WHERE subscriber = $subscriber;
+                CREATE type::thing('subscription_token', $key)
+                SET subscriber = $subscriber, expires_at = time::now() + $ttl;
+                COMMIT TRANSACTION;
+            ";
+            self.db()
+                .query(sql)
+                .bind(("subscriber", subscriber))
+                .bind(("key", &token))
+                .bind(("ttl", surrealdb::sql::Duration::from(ttl)))
+                .await
+                .map_err(|e| AppError::Custom(e.into()))?
+                .check()
+                .map_err(|e| AppError::Custom(e.into()))?;
+            Ok(token)
+        })
+        .await
+    }
+    /// Confirms the subscriber an unexpired `token` was issued to, and discards their
+    /// tokens. Returns `None` when the token is unknown or has expired.
+    #[tracing::instrument(name = "Confirming subscription", skip_all)]
+    pub async fn confirm_subscription_token(&self, token: &str) -> Result<Option<Subscriber>> {
+        observed("confirm_subscription_token", async {
+            let sql = "
+                BEGIN TRANSACTION;
+                LET $issued = (
+                    SELECT * FROM ONLY type::thing('subscription_token', $key)
+                    WHERE expires_at > time::now()
+                );
+                LET $confirmed = IF $issued != NONE {
+                    (UPDATE $issued.subscriber SET status = 'confirmed' WHERE status != NONE RETURN AFTER)
+                } ELSE {
+                    []
+                };
+                IF $issued != NONE {
+                    DELETE subscription_token WHERE subscriber = $issued.subscriber;
+                };
+                COMMIT TRANSACTION;
+                RETURN $confirmed;
+            ";
+            let mut res = self
+                .db()
+                .query(sql)
+                .bind(("key", token))
+                .await
+                .map_err(|e| AppError::Custom(e.into()))?;
+            let last = res.num_statements() - 1;
+            let confirmed: Vec<Subscriber> =
+                res.take(last).map_err(|_| AppError::DatabaseError)?;
+            Ok(confirmed.into_iter().next())
+        })
+        .await
+    }
+    /// Records that a subscription email is about to go to `subscriber`, unless one already
+    /// went out within `cooldown`. Returns whether the email may be sent.
+    #[tracing::instrument(name = "Claiming subscription email", skip(self))]
+    pub async fn claim_subscription_email(
+        &self,
+        subscriber: &Thing,
+        cooldown: Duration,
+    ) -> Result<bool> {
+        observed("claim_subscription_email", async {
+            let sql = "
+                UPDATE $subscriber SET last_notified_at = time::now()
+                WHERE last_notified_at = NONE OR last_notified_at <= time::now() - $cooldown
+                RETURN AFTER;
+            ";
+            let mut res = self
+                .db()
+                .query(sql)
+                .bind(("subscriber", subscriber))
+                .bind(("cooldown", surrealdb::sql::Duration::from(cooldown)))
+                .await
+                .map_err(|e| AppError::Custom(e.into()))?;
+            let claimed: Vec<Subscriber> = res.take(0).map_err(|_| AppError::DatabaseError)?;
+            Ok(!claimed.is_empty())
+        })
+        .await
+    }
+    /// Confirms a subscriber without a token, for subscribers added by an operator.
+    #[tracing::instrument(name = "Confirming subscriber", skip(self))]
+    pub async fn confirm_subscriber(&self, subscriber: &Thing) -> Result<()> {
+        observed("confirm_subscriber", async {
+            self.db()
+                .query("UPDATE $subscriber SET status = 'confirmed' WHERE status != NONE;")
+                .bind(("subscriber", subscriber))
+                .await
+                .map_err(|_| AppError::DatabaseError)?;
+            Ok(())
+        })
+        .await
+    }
+    /// Removes the confirmation tokens past their expiry. Returns how many were removed.
+    #[tracing::instrument(name = "Deleting expired confirmation tokens", skip(self))]
+    pub async fn delete_expired_tokens(&self) -> Result<usize> {
+        observed("delete_expired_tokens", async {
+            #[derive(serde::Deserialize)]
+            struct Token {}
+            let mut res = self
+                .db()
+                .query("DELETE subscription_token WHERE expires_at < time::now() RETURN BEFORE;")
+                .await
+                .map_err(|e| AppError::Custom(e.into()))?;
+            let deleted: Vec<Token> = res.take(0).map_err(|_| AppError::DatabaseError)?;
+            Ok(deleted.len())
+        })
+        .await
+    }
+    /// Removes the subscribers who signed up more than `age` ago and never confirmed, along
+    /// with their tokens. Returns how many subscribers were removed.
+    #[tracing::instrument(name = "Purging unconfirmed subscribers", skip(self))]
+    pub async fn purge_unconfirmed_subscribers(&self, age: Duration) -> Result<usize> {
+        observed("purge_unconfirmed_subscribers", async {
+            let sql = "
+                BEGIN TRANSACTION;
+                LET $purged = (
+                    SELECT VALUE id FROM subscriber
+                    WHERE status = 'pending_confirmation' AND subscribed_at < time::now() - $age
+                );
+                DELETE subscription_token WHERE subscriber INSIDE $purged;
+                DELETE subscriber WHERE id INSIDE $purged;
+                COMMIT TRANSACTION;
+                RETURN array::len($purged);
+            ";
+            let mut res = self
+                .db()
+                .query(sql)
+                .bind(("age", surrealdb::sql::Duration::from(age)))
+                .await
+                .map_err(|e| AppError::Custom(e.into()))?;
+            let last = res.num_statements() - 1;
+            let purged: Option<usize> = res.take(last).map_err(|_| AppError::DatabaseError)?;
+            Ok(purged.unwrap_or_default())
+        })
+        .await
+    }
+    #[tracing::instrument(
+        name = "Saving new newsletter issue in the database",
+        skip(self, issue)
+    )]
+    pub async fn add_issue(&self, issue: Issue) -> Result<Issue> {
+        observed("add_issue", async {
+            let issue: Option<Issue> = self
+                .db()
+                .create(issue.id.clone())
+                .content(issue)
+                .await
+                .map_err(|e| AppError::Custom(e.into()))?;
+            issue.ok_or(AppError::DatabaseError)
+        })
+        .await
+    }
+    #[tracing::instrument(name = "Retrieving all newsletter issues", skip(self))]
+    pub async fn get_issues(&self) -> Result<Vec<Issue>> {
+        observed("get_issues", async {
+            let mut res = self
+                .db()
+                .query("SELECT * FROM issue ORDER BY created_at DESC;")
+                .await
+                .map_err(|_| AppError::DatabaseError)?;
+            let issues: Vec<Issue> = res.take(0).map_err(|_| AppError::DatabaseError)?;
+            Ok(issues)
+        })
+        .await
+    }
+    #[tracing::instrument(name = "Retrieving newsletter issue by id", skip(self))]
+    pub async fn get_issue(&self, id: &str) -> Result<Issue> {
+        observed("get_issue", async {
+            let issue: Option<Issue> = self
+                .db()
+                .select(("issue", id))
+                .await
+                .map_err(|_| AppError::DatabaseError)?;
+            issue.ok_or(AppError::IssueNotFound)
+        })
+        .await
+    }
+    /// Saves the issue, provided nobody changed its status since it was read.
+    #[tracing::instrument(name = "Updating newsletter issue in the database", skip(self, issue))]
+    pub async fn update_issue(&self, issue: Issue, current: IssueStatus) -> Result<Issue> {
+        observed("update_issue", async {
+            let mut res = self
+                .db()
+                .query("UPDATE $id CONTENT $issue WHERE status = $current RETURN AFTER;")
+                .bind(("id", issue.id.clone()))
+                .bind(("issue", issue))
+                .bind(("current", current))
+                .await
+                .map_err(|e| AppError::Custom(e.into()))?;
+            let updated: Vec<Issue> = res.take(0).map_err(|_| AppError::DatabaseError)?;
+            updated.into_iter().next().ok_or(AppError::IssueNotEditable)
+        })
+        .await
+    }
+    #[tracing::instrument(name = "Deleting newsletter issue from the database", skip(self))]
+    pub async fn delete_issue(&self, id: &str) -> Result<()> {
+        observed("delete_issue", async {
+            let _deleted: Option<Issue> = self
+                .db()
+                .delete(("issue", id))
+                .await
+                .map_err(|_| AppError::DatabaseError)?;
+            Ok(())
+        })
+        .await
+    }
+    #[tracing::instrument(name = "Retrieving issues due to be sent", skip(self))]
+    pub async fn get_due_issues(&self) -> Result<Vec<Issue>> {
+        observed("get_due_issues", async {
+            let mut res = self
+                .db()
+                .query("SELECT * FROM issue WHERE status = 'scheduled' AND send_at <= time::now();")
+                .await
+                .map_err(|_| AppError::DatabaseError)?;
+            let issues: Vec<Issue> = res.take(0).map_err(|_| AppError::DatabaseError)?;
+            Ok(issues)
+        })
+        .await
+    }
+    /// Moves a scheduled issue to `sending` and queues a delivery for every confirmed
+    /// subscriber.
+    ///
+    /// Both happen in one transaction, so an issue is never enqueued twice. Returns `false`
+    /// when the issue was no longer scheduled.
+    #[tracing::instrument(name = "Enqueueing deliveries for newsletter issue", skip(self))]
+    pub async fn enqueue_issue(&self, issue: &Thing) -> Result<bool> {
+        observed("enqueue_issue", async {
+            let sql = "
+                BEGIN TRANSACTION;
+                LET $claimed = (UPDATE $issue SET status = 'sending' WHERE status = 'scheduled' RETURN AFTER);
+                IF array::len($claimed) > 0 {
+                    INSERT INTO delivery (
+                        SELECT $issue AS issue, id AS subscriber, 'queued' AS status,
+                            time::now() AS created_at, time::now() AS updated_at
+                        FROM subscriber WHERE status = 'confirmed'
+                    );
+                };
+                COMMIT TRANSACTION;
+                RETURN array::len($claimed) > 0;
+            ";
+            let mut res = self
+                .db()
+                .query(sql)
+                .bind(("issue", issue))
+                .await
+                .map_err(|e| AppError::Custom(e.into()))?;
+            let last = res.num_statements() - 1;
+            let claimed: Option<bool> = res.take(last).map_err(|_| AppError::DatabaseError)?;
+            Ok(claimed.unwrap_or_default())
+        })
+        .await
+    }
+    /// Leases the oldest queued delivery whose issue is currently being sent, skipping the
+    /// ones other workers hold. The caller releases the lease once the outcome is recorded.
+    #[tracing::instrument(name = "Claiming next queued delivery", skip(self))]
+    pub async fn claim_next_delivery(&self, ttl: Duration) -> Result<Option<Delivery>> {
+        observed("claim_next_delivery", async {
+            // Another worker may lease, or even send, a candidate between the select and
+            // the claim; the claim itself checks that it is still queued.
+            for delivery in self.queued_deliveries().await? {
+                if self.claim_delivery(&delivery, ttl).await? {
+                    return Ok(Some(delivery));
+                }
+            }
+            Ok(None)
+        })
+        .await
+    }
+    /// The oldest queued deliveries of sending issues that nobody holds a lease on.
+    async fn queued_deliveries(&self) -> Result<Vec<Delivery>> {
+        let sql = "
+            LET $leased = (SELECT VALUE meta::id(id) FROM lease WHERE expires_at > time::now());
+            SELECT * FROM delivery
+            WHERE status = 'queued' AND issue.status = 'sending'
+                AND $leased CONTAINSNOT string::concat('delivery:', meta::id(id))
+            ORDER BY created_at LIMIT $limit;
+        ";
         let mut res = self
-            .db
+            .db()
             .query(sql)
+            .bind(("limit", CLAIM_BATCH_SIZE))
             .await
             .map_err(|_| AppError::DatabaseError)?;
-        let s: Vec<Subscriber> = res.take(0).map_err(|_| AppError::DatabaseError)?;
-        match s.first() {
-            Some(s) => Ok(s.clone()),
-            None => Err(AppError::UserNotFound),
+        res.take(1).map_err(|_| AppError::DatabaseError)
+    }
+    /// Acquires the lease on `delivery`, provided it is still queued.
+    ///
+    /// Both are checked in one statement. A worker records the outcome before releasing its
+    /// lease, so a delivery that was sent in the meantime is never leased again.
+    async fn claim_delivery(&self, delivery: &Delivery, ttl: Duration) -> Result<bool> {
+        let sql = "
+            UPDATE type::thing('lease', $name)
+            SET owner = $owner, expires_at = time::now() + $ttl
+            WHERE (owner = NONE OR owner = $owner OR expires_at < time::now())
+                AND $delivery.status = 'queued'
+            RETURN AFTER;
+        ";
+        let mut res = self
+            .db()
+            .query(sql)
+            .bind(("name", delivery.lease_name()))
+            .bind(("owner", &*self.owner))
+            .bind(("ttl", surrealdb::sql::Duration::from(ttl)))
+            .bind(("delivery", &delivery.id))
+            .await
+            .map_err(|e| AppError::Custom(e.into()))?;
+        let leases: Vec<Lease> = res.take(0).map_err(|_| AppError::DatabaseError)?;
+        Ok(!leases.is_empty())
+    }
+    /// Counts the deliveries still waiting to be sent, across every sending issue.
+    #[tracing::instrument(name = "Counting queued deliveries", skip(self))]
+    pub async fn count_queued_deliveries(&self) -> Result<u64> {
+        observed("count_queued_deliveries", async {
+            #[derive(serde::Deserialize)]
+            struct Count {
+                count: u64,
+            }
+            let sql = "
+                SELECT count() FROM delivery
+                WHERE status = 'queued' AND issue.status = 'sending'
+                GROUP ALL;
+            ";
+            let mut res = self
+                .db()
+                .query(sql)
+                .await
+                .map_err(|_| AppError::DatabaseError)?;
+            let rows: Vec<Count> = res.take(0).map_err(|_| AppError::DatabaseError)?;
+            Ok(rows.into_iter().next().map_or(0, |row| row.count))
+        })
+        .await
+    }
+    /// Records how `delivery` went, provided it is still queued and this process still holds
+    /// its lease. Returns whether it was recorded.
+    ///
+    /// Both are checked in the same statement, so a worker that lost the lease mid-send cannot
+    /// overwrite the outcome of whichever replica took the delivery over.
+    #[tracing::instrument(name = "Recording delivery outcome", skip(self))]
+    pub async fn record_delivery_outcome(
+        &self,
+        delivery: &Delivery,
+        outcome: DeliveryOutcome,
+    ) -> Result<bool> {
+        observed("record_delivery_outcome", async {
+            let sql = "
+                UPDATE $delivery SET
+                    status = $status,
+                    message_id = $message_id,
+                    last_error = $last_error,
+                    updated_at = time::now()
+                WHERE status = 'queued' AND type::thing('lease', $lease).owner = $owner;
+            ";
+            let mut res = self
+                .db()
+                .query(sql)
+                .bind(("delivery", &delivery.id))
+                .bind(("lease", delivery.lease_name()))
+                .bind(("owner", &*self.owner))
+                .bind(("status", outcome.status))
+                .bind(("message_id", outcome.message_id))
+                .bind(("last_error", outcome.last_error))
+                .await
+                .map_err(|_| AppError::DatabaseError)?;
+            let recorded: Vec<Delivery> = res.take(0).map_err(|_| AppError::DatabaseError)?;
+            Ok(!recorded.is_empty())
+        })
+        .await
+    }
+    #[tracing::instrument(name = "Counting deliveries by status", skip(self))]
+    pub async fn get_delivery_counts(&self, issue: &Thing) -> Result<DeliveryCounts> {
+        observed("get_delivery_counts", async {
+            #[derive(serde::Deserialize)]
+            struct StatusCount {
+                status: DeliveryStatus,
+                count: u64,
+            }
+            let mut res = self
+                .db()
+                .query("SELECT status, count() FROM delivery WHERE issue = $issue GROUP BY status;")
+                .bind(("issue", issue))
+                .await
+                .map_err(|_| AppError::DatabaseError)?;
+            let rows: Vec<StatusCount> = res.take(0).map_err(|_| AppError::DatabaseError)?;
+            let mut counts = DeliveryCounts::default();
+            for row in rows {
+                let count = match row.status {
+                    DeliveryStatus::Queued => &mut counts.queued,
+                    DeliveryStatus::Sent => &mut counts.sent,
+                    DeliveryStatus::Failed => &mut counts.failed,
+                    DeliveryStatus::Bounced => &mut counts.bounced,
+                    DeliveryStatus::Suppressed => &mut counts.suppressed,
+                    DeliveryStatus::Cancelled => &mut counts.cancelled,
+                };
+                *count = row.count;
+            }
+            Ok(counts)
+        })
+        .await
+    }
+    #[tracing::instrument(name = "Retrieving failed deliveries", skip(self))]
+    pub async fn get_failed_deliveries(&self, issue: &Thing) -> Result<Vec<Delivery>> {
+        observed("get_failed_deliveries", async {
+            let sql = "
+                SELECT * FROM delivery
+                WHERE issue = $issue AND status INSIDE ['failed', 'bounced']
+                ORDER BY updated_at;
+            ";
+            let mut res = self
+                .db()
+                .query(sql)
+                .bind(("issue", issue))
+                .await
+                .map_err(|_| AppError::DatabaseError)?;
+            let deliveries: Vec<Delivery> = res.take(0).map_err(|_| AppError::DatabaseError)?;
+            Ok(deliveries)
+        })
+        .await
+    }
+    /// Puts failed deliveries back in the queue and reopens the issue if it was already sent.
+    #[tracing::instrument(name = "Requeueing failed deliveries", skip(self))]
+    pub async fn requeue_failed_deliveries(&self, issue: &Thing) -> Result<usize> {
+        observed("requeue_failed_deliveries", async {
+            let sql = "
+                BEGIN TRANSACTION;
+                LET $requeued = (
+                    UPDATE delivery SET status = 'queued', updated_at = time::now()
+                    WHERE issue = $issue AND status = 'failed'
+                    RETURN AFTER
+                );
+                IF array::len($requeued) > 0 {
+                    UPDATE $issue SET status = 'sending' WHERE status = 'sent';
+                };
+                COMMIT TRANSACTION;
+                RETURN array::len($requeued);
+            ";
+            let mut res = self
+                .db()
+                .query(sql)
+                .bind(("issue", issue))
+                .await
+                .map_err(|e| AppError::Custom(e.into()))?;
+            let last = res.num_statements() - 1;
+            let requeued: Option<usize> = res.take(last).map_err(|_| AppError::DatabaseError)?;
+            Ok(requeued.unwrap_or_default())
+        })
+        .await
+    }
+    /// Marks a `sending` issue as sent once no deliveries are left in the queue.
+    #[tracing::instrument(name = "Completing newsletter issue", skip(self))]
+    pub async fn complete_issue_if_delivered(&self, issue: &Thing) -> Result<()> {
+        observed("complete_issue_if_delivered", async {
+            let sql = "
+                LET $queued = (SELECT count() FROM delivery WHERE issue = $issue AND status = 'queued' GROUP ALL);
+                IF array::len($queued) = 0 {
+                    UPDATE $issue SET status = 'sent', published_at = time::now() WHERE status = 'sending';
+                };
+            ";
+            self.db()
+                .query(sql)
+                .bind(("issue", issue))
+                .await
+                .map_err(|_| AppError::DatabaseError)?;
+            Ok(())
+        })
+        .await
+    }
+    /// Cancels an issue that has not finished sending, along with its queued deliveries.
+    ///
+    /// Returns `false` when the issue was already sent, cancelled or still a draft.
+    #[tracing::instrument(name = "Cancelling newsletter issue", skip(self))]
+    pub async fn cancel_issue(&self, issue: &Thing) -> Result<bool> {
+        observed("cancel_issue", async {
+            let sql = "
+                BEGIN TRANSACTION;
+                LET $cancelled = (
+                    UPDATE $issue SET status = 'cancelled', updated_at = time::now()
+                    WHERE status INSIDE ['scheduled', 'sending', 'paused']
+                    RETURN AFTER
+                );
+                IF array::len($cancelled) > 0 {
+                    UPDATE delivery SET status = 'cancelled', updated_at = time::now()
+                    WHERE issue = $issue AND status = 'queued';
+                };
+                COMMIT TRANSACTION;
+                RETURN array::len($cancelled) > 0;
+            ";
+            let mut res = self
+                .db()
+                .query(sql)
+                .bind(("issue", issue))
+                .await
+                .map_err(|e| AppError::Custom(e.into()))?;
+            let last = res.num_statements() - 1;
+            let cancelled: Option<bool> = res.take(last).map_err(|_| AppError::DatabaseError)?;
+            Ok(cancelled.unwrap_or_default())
+        })
+        .await
+    }
+    #[tracing::instrument(name = "Retrieving subscriber by id", skip(self))]
+    pub async fn get_subscriber(&self, subscriber: &Thing) -> Result<Option<Subscriber>> {
+        observed("get_subscriber", async {
+            let subscriber: Option<Subscriber> = self
+                .db()
+                .select(subscriber.clone())
+                .await
+                .map_err(|_| AppError::DatabaseError)?;
+            Ok(subscriber)
+        })
+        .await
+    }
+    /// Inserts subscribers as they are, ids and timestamps included.
+    #[tracing::instrument(name = "Importing subscribers", skip_all, fields(count = subscribers.len()))]
+    pub async fn insert_subscribers(&self, subscribers: &[Subscriber]) -> Result<()> {
+        observed(
+            "insert_subscribers",
+            self.insert_rows("subscriber", subscribers),
+        )
+        .await
+    }
+    /// Inserts issues as they are, ids and timestamps included.
+    #[tracing::instrument(name = "Importing newsletter issues", skip_all, fields(count = issues.len()))]
+    pub async fn insert_issues(&self, issues: &[Issue]) -> Result<()> {
+        observed("insert_issues", self.insert_rows("issue", issues)).await
+    }
+    /// Inserts deliveries as they are, ids and timestamps included.
+    #[tracing::instrument(name = "Importing deliveries", skip_all, fields(count = deliveries.len()))]
+    pub async fn insert_deliveries(&self, deliveries: &[Delivery]) -> Result<()> {
+        observed(
+            "insert_deliveries",
+            self.insert_rows("delivery", deliveries),
+        )
+        .await
+    }
+    async fn insert_rows<T>(&self, table: &'static str, rows: &[T]) -> Result<()>
+    where
+        T: serde::Serialize + Clone + 'static,
+    {
+        for chunk in rows.chunks(INSERT_BATCH_SIZE) {
+            self.db()
+                .query(format!("INSERT INTO {table} $rows RETURN NONE;"))
+                .bind(("rows", chunk.to_vec()))
+                .await
+                .map_err(|e| AppError::Custom(e.into()))?
+                .check()
+                .map_err(|e| AppError::Custom(e.into()))?;
         }
+        Ok(())
     }
-    #[tracing::instrument]
-    pub async fn delete_subscriber(&self, subscriber: Subscriber) -> Result<()> {
-        tracing::debug!(
-            "Deleting subscriber with email: '{}' from database.",
-            subscriber.email
+    /// The owner id this process acquires leases under.
+    pub fn owner(&self) -> &str {
+        &self.owner
+    }
+    /// Claims the lease `name` for `ttl`. Succeeds when nobody holds it, when its holder let
+    /// it expire, or when this process already holds it, in which case it is extended.
+    #[tracing::instrument(name = "Acquiring lease", skip(self))]
+    pub async fn acquire_lease(&self, name: &str, ttl: Duration) -> Result<bool> {
+        observed("acquire_lease", async {
+            let sql = "
+                UPDATE type::thing('lease', $name)
+                SET owner = $owner, expires_at = time::now() + $ttl
+                WHERE owner = NONE OR owner = $owner OR expires_at < time::now()
+                RETURN AFTER;
+            ";
+            self.lease_query(sql, name, ttl).await
+        })
+        .await
+    }
+    /// Extends a lease this process holds. Returns `false` when it was lost to another
+    /// process after expiring.
+    #[tracing::instrument(name = "Renewing lease", skip(self))]
+    pub async fn renew_lease(&self, name: &str, ttl: Duration) -> Result<bool> {
+        observed("renew_lease", async {
+            let sql = "
+                UPDATE type::thing('lease', $name)
+                SET expires_at = time::now() + $ttl
+                WHERE owner = $owner
+                RETURN AFTER;
+            ";
+            self.lease_query(sql, name, ttl).await
+        })
+        .await
+    }
+    async fn lease_query(&self, sql: &str, name: &str, ttl: Duration) -> Result<bool> {
+        let mut res = self
+            .db()
+            .query(sql)
+            .bind(("name", name))
+            .bind(("owner", &*self.owner))
+            .bind(("ttl", surrealdb::sql::Duration::from(ttl)))
+            .await
+            .map_err(|e| AppError::Custom(e.into()))?;
+        let leases: Vec<Lease> = res.take(0).map_err(|_| AppError::DatabaseError)?;
+        Ok(!leases.is_empty())
+    }
+    /// Runs `work` while renewing the lease `name` every third of `ttl`, so that it does not
+    /// expire however long the work takes.
+    ///
+    /// A failed renewal does not interrupt `work`: cutting a send short could not unsend it.
+    /// Whatever `work` writes afterwards must instead check that the lease is still held, as
+    /// [`Storage::record_delivery_outcome`] does.
+    pub async fn hold_lease<F: std::future::Future>(
+        &self,
+        name: &str,
+        ttl: Duration,
+        work: F,
+    ) -> F::Output {
+        let heartbeat = async {
+            let mut interval = tokio::time::interval(ttl / 3);
+            interval.tick().await;
+            loop {
+                interval.tick().await;
+                match self.renew_lease(name, ttl).await {
+                    Ok(true) => {}
+                    Ok(false) => tracing::warn!(lease = name, "Lost a lease while holding it"),
+                    Err(e) => tracing::warn!(lease = name, error = ?e, "Failed to renew a lease"),
+                }
+            }
+        };
+        tokio::select! {
+            output = work => output,
+            _ = heartbeat => unreachable!("the heartbeat never ends"),
+        }
+    }
+    /// Looks up who holds the lease `name` and until when, expired leases included.
+    #[tracing::instrument(name = "Retrieving lease", skip(self))]
+    pub async fn get_lease(&self, name: &str) -> Result<Option<Lease>> {
+        observed("get_lease", async {
+            let mut res = self
+                .db()
+                .query("SELECT * FROM ONLY type::thing('lease', $name);")
+                .bind(("name", name))
+                .await
+                .map_err(|_| AppError::DatabaseError)?;
+            res.take(0).map_err(|_| AppError::DatabaseError)
+        })
+        .await
+    }
+    /// Gives up a lease this process holds.
+    #[tracing::instrument(name = "Releasing lease", skip(self))]
+    pub async fn release_lease(&self, name: &str) -> Result<()> {
+        observed("release_lease", async {
+            self.db()
+                .query("DELETE type::thing('lease', $name) WHERE owner = $owner;")
+                .bind(("name", name))
+                .bind(("owner", &*self.owner))
+                .await
+                .map_err(|e| AppError::Custom(e.into()))?;
+            Ok(())
+        })
+        .await
+    }
+    /// Gives up every lease this process holds, so other replicas can take over right away.
+    #[tracing::instrument(name = "Releasing all leases", skip(self))]
+    pub async fn release_all_leases(&self) -> Result<usize> {
+        observed("release_all_leases", async {
+            let mut res = self
+                .db()
+                .query("DELETE lease WHERE owner = $owner RETURN BEFORE;")
+                .bind(("owner", &*self.owner))
+                .await
+                .map_err(|e| AppError::Custom(e.into()))?;
+            let released: Vec<Lease> = res.take(0).map_err(|_| AppError::DatabaseError)?;
+            Ok(released.len())
+        })
+        .await
+    }
+    /// Takes a token from the send bucket `name`, which every process using this database
+    /// draws from, after refilling it at `rate` tokens per second up to `burst`.
+    ///
+    /// Returns `None` when a token was taken, or how long to wait before trying again. The
+    /// refill, the check and the take happen in one `UPDATE`, so concurrent senders cannot
+    /// take the same token.
+    #[tracing::instrument(name = "Taking a send token", skip(self))]
+    pub async fn take_send_token(
+        &self,
+        name: &str,
+        rate: f64,
+        burst: f64,
+    ) -> Result<Option<Duration>> {
+        observed("take_send_token", async {
+            #[derive(serde::Deserialize)]
+            struct Taken {
+                granted: bool,
+                wait_ms: f64,
+            }
+            let sql = "
+                UPDATE type::thing('send_bucket', $name) SET
+                    tokens = IF tokens = NONE { $burst } ELSE {
+                        math::min([$burst, tokens + duration::millis(time::now() - refilled_at) * $rate / 1000])
+                    },
+                    refilled_at = time::now(),
+                    granted = tokens >= 1 AND (blocked_until = NONE OR blocked_until <= time::now()),
+                    tokens = IF granted { tokens - 1 } ELSE { tokens }
+                RETURN granted, IF granted { 0 }
+                    ELSE IF blocked_until > time::now() { duration::millis(blocked_until - time::now()) }
+                    ELSE { math::ceil((1 - tokens) / $rate * 1000) } AS wait_ms;
+            ";
+            let mut res = self
+                .db()
+                .query(sql)
+                .bind(("name", name))
+                .bind(("rate", rate))
+                .bind(("burst", burst))
+                .await
+                .map_err(|_| AppError::DatabaseError)?;
+            let taken: Option<Taken> = res.take(0).map_err(|_| AppError::DatabaseError)?;
+            let taken = taken.ok_or(AppError::DatabaseError)?;
+            Ok((!taken.granted).then(|| Duration::from_secs_f64(taken.wait_ms.max(1.0) / 1000.0)))
+        })
+        .await
+    }
+    /// Empties the send bucket `name` and keeps it empty for at least `delay`, for every
+    /// process drawing from it.
+    #[tracing::instrument(name = "Blocking the send bucket", skip(self))]
+    pub async fn block_send_bucket(&self, name: &str, delay: Duration) -> Result<()> {
+        observed("block_send_bucket", async {
+            let sql = "
+                UPDATE type::thing('send_bucket', $name) SET
+                    tokens = 0.0,
+                    refilled_at = time::now(),
+                    granted = false,
+                    blocked_until = IF blocked_until > time::now() + $delay { blocked_until }
+                        ELSE { time::now() + $delay };
+            ";
+            self.db()
+                .query(sql)
+                .bind(("name", name))
+                .bind(("delay", surrealdb::sql::Duration::from(delay)))
+                .await
+                .map_err(|_| AppError::DatabaseError)?
+                .check()
+                .map_err(|_| AppError::DatabaseError)?;
+            Ok(())
+        })
+        .await
+    }
+}
+/// A random, URL-safe token that is impractical to guess.
+fn generate_token() -> String {
+    use rand::{distributions::Alphanumeric, Rng};
+    rand::thread_rng()
+        .sample_iter(&Alphanumeric)
+        .take(TOKEN_LENGTH)
+        .map(char::from)
+        .collect()
+}
+#[cfg(test)]
+impl Storage {
+    /// A storage whose client never connected, so every query fails.
+    pub(crate) fn unreachable() -> Storage {
+        Storage {
+            client: Arc::new(RwLock::new(Surreal::init())),
+            settings: Arc::new(tests::settings(0)),
+            owner: "unreachable".into(),
+        }
+    }
+}
+/// Records the latency of a storage operation and counts the database errors it returns.
+async fn observed<T>(
+    operation: &'static str,
+    query: impl std::future::Future<Output = Result<T>>,
+) -> Result<T> {
+    let start = std::time::Instant::now();
+    let result = query.await;
+    metrics::histogram!("storage_query_duration_seconds", "operation" => operation)
+        .record(start.elapsed().as_secs_f64());
+    if let Err(AppError::DatabaseError | AppError::Custom(_)) = &result {
+        metrics::counter!("storage_query_errors_total", "operation" => operation).increment(1);
+    }
+    result
+}
+/// Opens a connection, signs in and makes sure the schema is in place.
+async fn connect(settings: &DatabaseSettings) -> Result<Surreal<Any>> {
+    let timeout = Duration::from_millis(settings.connection.connect_timeout_ms);
+    let connecting = async {
+        let address = format!("ws://{}", settings.connection_string().expose_secret());
+        let db = surrealdb::engine::any::connect(address).await?;
+        db.signin(settings.root()).await?;
+        db.use_ns(&settings.namespace)
+            .use_db(&settings.database_name)
+            .await?;
+        apply_schema(&db).await?;
+        Ok(db)
+    };
+    tokio::time::timeout(timeout, connecting)
+        .await
+        .map_err(|_| anyhow::anyhow!("no answer from the database within {timeout:?}"))?
+}
+async fn apply_schema(db: &Surreal<Any>) -> Result<()> {
+    for q in SCHEMA {
+        let qr = db.query(*q).await?;
+        debug!("Query result: {qr:?}");
+    }
+    Ok(())
+}
+/// Exponentially growing delays between connection attempts.
+struct Backoff {
+    next: Duration,
+    max: Duration,
+}
+impl Backoff {
+    fn new(settings: &DatabaseConnectionSettings) -> Self {
+        Self {
+            next: Duration::from_millis(settings.initial_backoff_ms),
+            max: Duration::from_millis(settings.max_backoff_ms),
+        }
+    }
+    fn next_delay(&mut self) -> Duration {
+        let delay = self.next;
+        self.next = (self.next * 2).min(self.max);
+        delay
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashSet;
+
+    use secrecy::Secret;
+
+    use super::*;
+    use crate::{
+        issue::IssueData,
+        seed::{generate, SeedOptions},
+        subscriber::SubscriberStatus,
+    };
+
+    pub(super) fn settings(port: u16) -> DatabaseSettings {
+        DatabaseSettings {
+            username: "root".into(),
+            password: Secret::new("root".into()),
+            port,
+            host: "127.0.0.1".into(),
+            database_name: "newsletter".into(),
+            namespace: "zero2prod".into(),
+            connection: DatabaseConnectionSettings::default(),
+        }
+    }
+
+    /// A fresh in-process database with the schema applied.
+    async fn in_memory() -> Storage {
+        let db = surrealdb::engine::any::connect("mem://").await.unwrap();
+        db.use_ns("zero2prod").use_db("newsletter").await.unwrap();
+        apply_schema(&db).await.unwrap();
+        Storage {
+            client: Arc::new(RwLock::new(db)),
+            settings: Arc::new(settings(0)),
+            owner: "replica-0".into(),
+        }
+    }
+
+    async fn pending_subscriber(storage: &Storage, email: &str) -> Subscriber {
+        storage
+            .add_subscriber(FormData {
+                email: email.into(),
+                name: "Ursula".into(),
+            })
+            .await
+            .unwrap()
+    }
+
+    /// Another process sharing the same database.
+    fn replica(storage: &Storage, owner: &str) -> Storage {
+        Storage {
+            owner: owner.into(),
+            ..storage.clone()
+        }
+    }
+
+    #[test]
+    fn backoff_doubles_up_to_the_maximum() {
+        let mut backoff = Backoff::new(&DatabaseConnectionSettings {
+            initial_backoff_ms: 100,
+            max_backoff_ms: 300,
+            ..Default::default()
+        });
+        let delays: Vec<_> = (0..4).map(|_| backoff.next_delay().as_millis()).collect();
+        assert_eq!(delays, [100, 200, 300, 300]);
+    }
+
+    #[tokio::test]
+    async fn init_gives_up_after_the_configured_attempts() {
+        // Nothing listens on port 1, so every attempt is refused straight away.
+        let settings = DatabaseSettings {
+            connection: DatabaseConnectionSettings {
+                startup_attempts: 3,
+                initial_backoff_ms: 1,
+                max_backoff_ms: 1,
+                ..Default::default()
+            },
+            ..settings(1)
+        };
+
+        assert!(Storage::init(settings).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn a_lease_is_exclusive_until_it_expires() {
+        let first = in_memory().await;
+        let second = replica(&first, "replica-1");
+        let ttl = Duration::from_millis(200);
+
+        assert!(first.acquire_lease("job", ttl).await.unwrap());
+        assert!(!second.acquire_lease("job", ttl).await.unwrap());
+        assert!(first.acquire_lease("job", ttl).await.unwrap());
+        tokio::time::sleep(Duration::from_millis(300)).await;
+        assert!(second.acquire_lease("job", ttl).await.unwrap());
+        assert!(!first.renew_lease("job", ttl).await.unwrap());
+        let lease = first.get_lease("job").await.unwrap().unwrap();
+        assert_eq!(lease.owner, "replica-1");
+    }
+
+    #[tokio::test]
+    async fn released_leases_can_be_taken_over_right_away() {
+        let first = in_memory().await;
+        let second = replica(&first, "replica-1");
+        let ttl = Duration::from_secs(30);
+        for name in ["a", "b", "c"] {
+            assert!(first.acquire_lease(name, ttl).await.unwrap());
+        }
+
+        first.release_lease("a").await.unwrap();
+        assert!(second.acquire_lease("a", ttl).await.unwrap());
+        // Only the holder can release a lease.
+        first.release_lease("a").await.unwrap();
+        assert!(!first.acquire_lease("a", ttl).await.unwrap());
+        assert_eq!(first.release_all_leases().await.unwrap(), 2);
+        assert!(second.acquire_lease("b", ttl).await.unwrap());
+        assert!(second.acquire_lease("c", ttl).await.unwrap());
+    }
+
+    #[tokio::test]
+    async fn a_held_lease_outlives_its_ttl() {
+        let first = in_memory().await;
+        let second = replica(&first, "replica-1");
+        let ttl = Duration::from_millis(300);
+        assert!(first.acquire_lease("slow", ttl).await.unwrap());
+
+        let taken_over = first
+            .hold_lease("slow", ttl, async {
+                tokio::time::sleep(Duration::from_millis(700)).await;
+                second.acquire_lease("slow", ttl).await.unwrap()
+            })
+            .await;
+
+        assert!(!taken_over);
+    }
+
+    #[tokio::test]
+    async fn concurrent_claims_have_exactly_one_winner() {
+        let storage = in_memory().await;
+        let claims = (0..16).map(|i| {
+            let replica = replica(&storage, &format!("replica-{i}"));
+            tokio::spawn(async move {
+                replica
+                    .acquire_lease("contended", Duration::from_secs(30))
+                    .await
+                    .unwrap()
+            })
+        });
+
+        let mut winners = 0;
+        for claim in claims.collect::<Vec<_>>() {
+            winners += claim.await.unwrap() as usize;
+        }
+
+        assert_eq!(winners, 1);
+    }
+
+    #[tokio::test]
+    async fn concurrent_workers_claim_each_delivery_once() {
+        let storage = in_memory().await;
+        let data = generate(
+            &SeedOptions {
+                subscribers: 40,
+                issues: 0,
+                seed: 1,
+            },
+            chrono::Utc::now(),
         );
-        let _deleted: Option<Subscriber> = self
-            .db
-            .delete(subscriber.id)
+        storage.insert_subscribers(&data.subscribers).await.unwrap();
+        let mut issue = Issue::try_from(IssueData {
+            title: "Contended".into(),
+            markdown: "Once each".into(),
+        })
+        .unwrap();
+        issue.send_now().unwrap();
+        let issue = storage.add_issue(issue).await.unwrap();
+        assert!(storage.enqueue_issue(&issue.id).await.unwrap());
+
+        let workers = (0..4).map(|i| {
+            let replica = replica(&storage, &format!("replica-{i}"));
+            tokio::spawn(async move {
+                let mut claimed = Vec::new();
+                while let Some(delivery) = replica
+                    .claim_next_delivery(Duration::from_secs(30))
+                    .await
+                    .unwrap()
+                {
+                    claimed.push(delivery.id.to_string());
+                }
+                claimed
+            })
+        });
+        let mut claimed = Vec::new();
+        for worker in workers.collect::<Vec<_>>() {
+            claimed.extend(worker.await.unwrap());
+        }
+
+        let confirmed = data
+            .subscribers
+            .iter()
+            .filter(|s| s.status == SubscriberStatus::Confirmed)
+            .count();
+        let unique: HashSet<_> = claimed.iter().collect();
+        assert_eq!(claimed.len(), confirmed);
+        assert_eq!(unique.len(), claimed.len());
+    }
+
+    #[tokio::test]
+    async fn a_confirmation_token_confirms_its_subscriber_once() {
+        let storage = in_memory().await;
+        let subscriber = pending_subscriber(&storage, "ursula@example.com").await;
+        assert_eq!(subscriber.status, SubscriberStatus::PendingConfirmation);
+        let ttl = Duration::from_secs(60);
+        let stale = storage
+            .create_confirmation_token(&subscriber.id, ttl)
             .await
-            .map_err(|_| AppError::DatabaseError)?;
-        Ok(())
+            .unwrap();
+        let token = storage
+            .create_confirmation_token(&subscriber.id, ttl)
+            .await
+            .unwrap();
+
+        // Only the latest link works.
+        assert!(storage
+            .confirm_subscription_token(&stale)
+            .await
+            .unwrap()
+            .is_none());
+        let confirmed = storage
+            .confirm_subscription_token(&token)
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(confirmed.id, subscriber.id);
+        assert_eq!(confirmed.status, SubscriberStatus::Confirmed);
+        assert!(storage
+            .confirm_subscription_token(&token)
+            .await
+            .unwrap()
+            .is_none());
+    }
+
+    #[tokio::test]
+    async fn expired_tokens_confirm_nothing_and_are_cleaned_up() {
+        let storage = in_memory().await;
+        let subscriber = pending_subscriber(&storage, "ursula@example.com").await;
+        let token = storage
+            .create_confirmation_token(&subscriber.id, Duration::from_millis(100))
+            .await
+            .unwrap();
+        tokio::time::sleep(Duration::from_millis(200)).await;
+
+        assert!(storage
+            .confirm_subscription_token(&token)
+            .await
+            .unwrap()
+            .is_none());
+        assert_eq!(storage.delete_expired_tokens().await.unwrap(), 1);
+        assert_eq!(storage.delete_expired_tokens().await.unwrap(), 0);
+        let subscriber = storage.get_subscriber(&subscriber.id).await.unwrap();
+        assert_eq!(
+            subscriber.unwrap().status,
+            SubscriberStatus::PendingConfirmation
+        );
+    }
+
+    #[tokio::test]
+    async fn only_stale_unconfirmed_subscribers_are_purged() {
+        let storage = in_memory().await;
+        let stale = pending_subscriber(&storage, "stale@example.com").await;
+        storage
+            .create_confirmation_token(&stale.id, Duration::from_secs(60))
+            .await
+            .unwrap();
+        let confirmed = pending_subscriber(&storage, "confirmed@example.com").await;
+        storage.confirm_subscriber(&confirmed.id).await.unwrap();
+        tokio::time::sleep(Duration::from_millis(200)).await;
+        let recent = pending_subscriber(&storage, "recent@example.com").await;
+
+        let purged = storage
+            .purge_unconfirmed_subscribers(Duration::from_millis(100))
+            .await
+            .unwrap();
+
+        assert_eq!(purged, 1);
+        let left: HashSet<_> = storage
+            .get_subscribers()
+            .await
+            .unwrap()
+            .into_iter()
+            .map(|s| s.email)
+            .collect();
+        assert_eq!(left, HashSet::from([confirmed.email, recent.email]));
+        // The purged subscriber's token went with them.
+        assert_eq!(storage.delete_expired_tokens().await.unwrap(), 0);
+        let mut res = storage
+            .db()
+            .query("SELECT count() FROM subscription_token GROUP ALL;")
+            .await
+            .unwrap();
+        let count: Option<usize> = res.take("count").unwrap();
+        assert_eq!(count, None);
+    }
+
+    #[tokio::test]
+    async fn issues_only_go_to_confirmed_subscribers() {
+        let storage = in_memory().await;
+        pending_subscriber(&storage, "pending@example.com").await;
+        let confirmed = pending_subscriber(&storage, "confirmed@example.com").await;
+        storage.confirm_subscriber(&confirmed.id).await.unwrap();
+        let mut issue = Issue::try_from(IssueData {
+            title: "Confirmed only".into(),
+            markdown: "Hello".into(),
+        })
+        .unwrap();
+        issue.send_now().unwrap();
+        let issue = storage.add_issue(issue).await.unwrap();
+
+        assert!(storage.enqueue_issue(&issue.id).await.unwrap());
+
+        let counts = storage.get_delivery_counts(&issue.id).await.unwrap();
+        assert_eq!(counts.queued, 1);
+        let delivery = storage
+            .claim_next_delivery(Duration::from_secs(30))
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(delivery.subscriber, confirmed.id);
+    }
+
+    #[tokio::test]
+    async fn a_delivery_sent_after_the_select_is_not_claimed_again() {
+        let first = in_memory().await;
+        let second = replica(&first, "replica-1");
+        let confirmed = pending_subscriber(&first, "confirmed@example.com").await;
+        first.confirm_subscriber(&confirmed.id).await.unwrap();
+        let mut issue = Issue::try_from(IssueData {
+            title: "Once".into(),
+            markdown: "Only once".into(),
+        })
+        .unwrap();
+        issue.send_now().unwrap();
+        let issue = first.add_issue(issue).await.unwrap();
+        assert!(first.enqueue_issue(&issue.id).await.unwrap());
+        let ttl = Duration::from_secs(30);
+
+        let stale = first.queued_deliveries().await.unwrap();
+        let delivery = second.claim_next_delivery(ttl).await.unwrap().unwrap();
+        assert!(second
+            .record_delivery_outcome(&delivery, sent())
+            .await
+            .unwrap());
+        second.release_lease(&delivery.lease_name()).await.unwrap();
+
+        assert_eq!(stale.len(), 1);
+        assert!(!first.claim_delivery(&stale[0], ttl).await.unwrap());
+        assert!(first.claim_next_delivery(ttl).await.unwrap().is_none());
+    }
+
+    #[tokio::test]
+    async fn subscription_emails_are_throttled_per_address() {
+        let storage = in_memory().await;
+        let first = pending_subscriber(&storage, "first@example.com").await;
+        let second = pending_subscriber(&storage, "second@example.com").await;
+        let cooldown = Duration::from_millis(200);
+
+        assert!(storage
+            .claim_subscription_email(&first.id, cooldown)
+            .await
+            .unwrap());
+        assert!(!storage
+            .claim_subscription_email(&first.id, cooldown)
+            .await
+            .unwrap());
+        assert!(storage
+            .claim_subscription_email(&second.id, cooldown)
+            .await
+            .unwrap());
+        tokio::time::sleep(Duration::from_millis(300)).await;
+        assert!(storage
+            .claim_subscription_email(&first.id, cooldown)
+            .await
+            .unwrap());
+    }
+
+    #[tokio::test]
+    async fn subscribers_are_found_by_emails_containing_quotes() {
+        let storage = in_memory().await;
+        let added = pending_subscriber(&storage, "o'brien@example.com").await;
+
+        let found = storage
+            .get_subscriber_by_email("o'brien@example.com")
+            .await
+            .unwrap();
+        assert_eq!(found.id, added.id);
+        assert!(matches!(
+            storage
+                .get_subscriber_by_email("x' OR true OR email = 'y")
+                .await,
+            Err(AppError::UserNotFound)
+        ));
+    }
+
+    fn sent() -> DeliveryOutcome {
+        DeliveryOutcome {
+            status: DeliveryStatus::Sent,
+            message_id: None,
+            last_error: None,
+        }
+    }
+
+    #[tokio::test]
+    async fn outcomes_are_only_recorded_by_the_lease_holder() {
+        let first = in_memory().await;
+        let second = replica(&first, "replica-1");
+        let confirmed = pending_subscriber(&first, "confirmed@example.com").await;
+        first.confirm_subscriber(&confirmed.id).await.unwrap();
+        let mut issue = Issue::try_from(IssueData {
+            title: "Slow".into(),
+            markdown: "Sent once".into(),
+        })
+        .unwrap();
+        issue.send_now().unwrap();
+        let issue = first.add_issue(issue).await.unwrap();
+        assert!(first.enqueue_issue(&issue.id).await.unwrap());
+        let ttl = Duration::from_millis(200);
+
+        let delivery = first.claim_next_delivery(ttl).await.unwrap().unwrap();
+        // The lease runs out mid-send and another replica takes the delivery over.
+        tokio::time::sleep(Duration::from_millis(300)).await;
+        let taken_over = second.claim_next_delivery(ttl).await.unwrap().unwrap();
+        assert_eq!(taken_over.id, delivery.id);
+
+        assert!(!first
+            .record_delivery_outcome(&delivery, sent())
+            .await
+            .unwrap());
+        assert!(second
+            .record_delivery_outcome(&taken_over, sent())
+            .await
+            .unwrap());
+        assert!(!second
+            .record_delivery_outcome(&taken_over, sent())
+            .await
+            .unwrap());
+    }
+
+    #[tokio::test]
+    async fn replicas_draw_from_the_same_send_bucket() {
+        let first = in_memory().await;
+        let second = replica(&first, "replica-1");
+
+        assert!(first
+            .take_send_token("email", 1.0, 2.0)
+            .await
+            .unwrap()
+            .is_none());
+        assert!(second
+            .take_send_token("email", 1.0, 2.0)
+            .await
+            .unwrap()
+            .is_none());
+        let wait = first
+            .take_send_token("email", 1.0, 2.0)
+            .await
+            .unwrap()
+            .unwrap();
+        assert!(wait > Duration::from_millis(900) && wait <= Duration::from_secs(1));
+        assert!(second
+            .take_send_token("other", 1.0, 2.0)
+            .await
+            .unwrap()
+            .is_none());
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+    async fn concurrent_senders_never_exceed_the_burst() {
+        let storage = in_memory().await;
+
+        let senders = (0..8).map(|i| {
+            let replica = replica(&storage, &format!("replica-{i}"));
+            tokio::spawn(async move { replica.take_send_token("email", 0.001, 3.0).await })
+        });
+        let mut granted = 0;
+        for sender in senders {
+            if let Ok(None) = sender.await.unwrap() {
+                granted += 1;
+            }
+        }
+
+        assert_eq!(granted, 3);
+    }
+
+    #[tokio::test]
+    async fn a_blocked_send_bucket_holds_every_replica_back() {
+        let first = in_memory().await;
+        let second = replica(&first, "replica-1");
+
+        first
+            .block_send_bucket("email", Duration::from_secs(3))
+            .await
+            .unwrap();
+        first
+            .block_send_bucket("email", Duration::from_secs(1))
+            .await
+            .unwrap();
+
+        let wait = second
+            .take_send_token("email", 100.0, 10.0)
+            .await
+            .unwrap()
+            .unwrap();
+        assert!(wait > Duration::from_secs(2) && wait <= Duration::from_secs(3));
     }
 }
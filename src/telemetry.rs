@@ -1,23 +1,290 @@
-use tracing::{subscriber::set_global_default, Subscriber};
+use std::sync::Mutex;
+
+use axum::http::{HeaderMap, Request};
+use metrics_exporter_prometheus::{Matcher, PrometheusBuilder, PrometheusHandle};
+use opentelemetry::{global, trace::TracerProvider};
+use opentelemetry_http::{HeaderExtractor, HeaderInjector};
+use opentelemetry_otlp::{SpanExporter, WithExportConfig};
+use opentelemetry_sdk::{
+    propagation::TraceContextPropagator,
+    trace::{SdkTracerProvider, Tracer},
+    Resource,
+};
+use rolling_file::{BasicRollingFileAppender, RollingConditionBasic};
+use tower_http::trace::MakeSpan;
+use tracing::{subscriber::set_global_default, Span, Subscriber};
 use tracing_bunyan_formatter::{BunyanFormattingLayer, JsonStorageLayer};
 use tracing_log::LogTracer;
+use tracing_opentelemetry::OpenTelemetrySpanExt;
 use tracing_subscriber::fmt::MakeWriter;
-use tracing_subscriber::{layer::SubscriberExt, EnvFilter, Registry};
+use tracing_subscriber::{layer::SubscriberExt, registry::LookupSpan, EnvFilter, Layer, Registry};
+
+use crate::{
+    configuration::{LogFileSettings, LogFormat, LogRotation, LoggingSettings, TelemetrySettings},
+    request_id::REQUEST_ID_HEADER,
+};
 
-pub fn get_subscriber<T, M>(name: T, env_filter: T, sink: M) -> impl Subscriber + Send + Sync
+/// Builds the subscriber described by `settings`: `sink` receives the formatted logs and,
+/// when configured, a rotating file receives a copy in the same format.
+pub fn get_subscriber<T, M>(
+    name: T,
+    settings: &LoggingSettings,
+    sink: M,
+    tracer: Tracer,
+) -> crate::Result<impl Subscriber + Send + Sync>
 where
     T: ToString,
     M: for<'de> MakeWriter<'de> + Send + Sync + 'static,
 {
-    let env_filter = EnvFilter::try_from_default_env()
-        .unwrap_or_else(|_| EnvFilter::new(env_filter.to_string()));
-    let formatting_layer = BunyanFormattingLayer::new(name.to_string(), sink);
-    Registry::default()
+    let env_filter = match EnvFilter::try_from_default_env() {
+        Ok(filter) => filter,
+        Err(_) => EnvFilter::try_new(settings.filter())?,
+    };
+    let name = name.to_string();
+    let file_layer = match &settings.file {
+        Some(file) => Some(formatting_layer(
+            name.clone(),
+            settings.format,
+            Mutex::new(rolling_file(file)?),
+            false,
+        )),
+        None => None,
+    };
+    Ok(Registry::default()
         .with(env_filter)
         .with(JsonStorageLayer)
-        .with(formatting_layer)
+        .with(formatting_layer(name, settings.format, sink, true))
+        .with(file_layer)
+        .with(tracing_opentelemetry::layer().with_tracer(tracer)))
+}
+fn formatting_layer<S, M>(
+    name: String,
+    format: LogFormat,
+    writer: M,
+    ansi: bool,
+) -> Box<dyn Layer<S> + Send + Sync>
+where
+    S: Subscriber + for<'a> LookupSpan<'a>,
+    M: for<'de> MakeWriter<'de> + Send + Sync + 'static,
+{
+    match format {
+        LogFormat::Bunyan => Box::new(BunyanFormattingLayer::new(name, writer)),
+        LogFormat::Pretty => Box::new(
+            tracing_subscriber::fmt::layer()
+                .pretty()
+                .with_ansi(ansi)
+                .with_writer(writer),
+        ),
+        LogFormat::Compact => Box::new(
+            tracing_subscriber::fmt::layer()
+                .compact()
+                .with_ansi(ansi)
+                .with_writer(writer),
+        ),
+    }
+}
+/// Opens the log file, creating its directory if needed. Writes are unbuffered so nothing is
+/// lost when the process exits.
+fn rolling_file(settings: &LogFileSettings) -> crate::Result<BasicRollingFileAppender> {
+    if let Some(dir) = settings.path.parent() {
+        std::fs::create_dir_all(dir)?;
+    }
+    let condition = match settings.rotation {
+        LogRotation::Daily => RollingConditionBasic::new().daily(),
+        LogRotation::Hourly => RollingConditionBasic::new().hourly(),
+        LogRotation::Size => {
+            RollingConditionBasic::new().max_size(settings.max_size_mb * 1024 * 1024)
+        }
+    };
+    let appender = BasicRollingFileAppender::new_with_buffer_capacity(
+        &settings.path,
+        condition,
+        settings.max_files,
+        0,
+    )?;
+    Ok(appender)
+}
+/// Builds the OpenTelemetry tracer provider and installs the W3C trace-context propagator.
+///
+/// Spans are always given OpenTelemetry ids so `traceparent` can be propagated, but they are
+/// only exported when an OTLP endpoint is configured. Keep the provider around and shut it
+/// down on exit so buffered spans are flushed.
+pub fn init_tracer(name: &str, settings: &TelemetrySettings) -> crate::Result<SdkTracerProvider> {
+    global::set_text_map_propagator(TraceContextPropagator::new());
+    let mut builder = SdkTracerProvider::builder().with_resource(
+        Resource::builder()
+            .with_service_name(name.to_string())
+            .build(),
+    );
+    if let Some(endpoint) = &settings.otlp_endpoint {
+        let exporter = SpanExporter::builder()
+            .with_http()
+            .with_endpoint(endpoint)
+            .build()?;
+        builder = builder.with_batch_exporter(exporter);
+    }
+    Ok(builder.build())
+}
+/// Returns a tracer from `provider`, ready to be handed to [`get_subscriber`].
+pub fn tracer(provider: &SdkTracerProvider, name: &'static str) -> Tracer {
+    provider.tracer(name)
+}
+/// Writes the current span's trace context into outgoing request headers.
+pub fn inject_trace_context(headers: &mut HeaderMap) {
+    let context = Span::current().context();
+    global::get_text_map_propagator(|propagator| {
+        propagator.inject_context(&context, &mut HeaderInjector(headers))
+    });
+}
+/// Creates request spans that carry the request id and continue the trace named by an
+/// incoming `traceparent` header.
+#[derive(Clone, Debug)]
+pub struct TraceContextMakeSpan;
+impl<B> MakeSpan<B> for TraceContextMakeSpan {
+    fn make_span(&mut self, request: &Request<B>) -> Span {
+        let request_id = request
+            .headers()
+            .get(&REQUEST_ID_HEADER)
+            .and_then(|value| value.to_str().ok())
+            .unwrap_or_default();
+        let span = tracing::info_span!(
+            "request",
+            method = %request.method(),
+            uri = %request.uri(),
+            version = ?request.version(),
+            request_id,
+            headers = ?request.headers(),
+        );
+        let parent = global::get_text_map_propagator(|propagator| {
+            propagator.extract(&HeaderExtractor(request.headers()))
+        });
+        if let Err(e) = span.set_parent(parent) {
+            tracing::debug!(error = ?e, "Failed to attach the remote trace context");
+        }
+        span
+    }
 }
 pub fn init_subscriber(subscriber: impl Subscriber + Send + Sync) {
     LogTracer::init().expect("Failed to set logger");
     set_global_default(subscriber).expect("Failed to set subscriber")
 }
+
+/// Latency buckets, in seconds, shared by every `*_duration_seconds` histogram.
+const DURATION_BUCKETS: &[f64] = &[
+    0.001, 0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0,
+];
+/// Installs the global Prometheus recorder and returns the handle used to render it.
+pub fn init_metrics() -> crate::Result<PrometheusHandle> {
+    let handle = PrometheusBuilder::new()
+        .set_buckets_for_metric(
+            Matcher::Suffix("duration_seconds".to_string()),
+            DURATION_BUCKETS,
+        )?
+        .install_recorder()?;
+    Ok(handle)
+}
+
+#[cfg(test)]
+mod tests {
+    use wiremock::{
+        matchers::{method, path},
+        Mock, MockServer, ResponseTemplate,
+    };
+
+    use super::*;
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn spans_are_exported_to_the_configured_collector() {
+        let collector = MockServer::start().await;
+        Mock::given(method("POST"))
+            .and(path("/v1/traces"))
+            .respond_with(ResponseTemplate::new(200))
+            .expect(1..)
+            .mount(&collector)
+            .await;
+        let settings = TelemetrySettings {
+            otlp_endpoint: Some(format!("{}/v1/traces", collector.uri())),
+            ..Default::default()
+        };
+        let provider = init_tracer("test", &settings).unwrap();
+        let subscriber = get_subscriber(
+            "test",
+            &LoggingSettings::default(),
+            std::io::sink,
+            tracer(&provider, "test"),
+        )
+        .unwrap();
+
+        tracing::subscriber::with_default(subscriber, || {
+            tracing::info_span!("exported").in_scope(|| {});
+        });
+        tokio::task::spawn_blocking(move || provider.shutdown())
+            .await
+            .unwrap()
+            .unwrap();
+    }
+
+    #[test]
+    fn trace_context_is_injected_from_the_current_span() {
+        let provider = init_tracer("test", &TelemetrySettings::default()).unwrap();
+        let subscriber = get_subscriber(
+            "test",
+            &LoggingSettings::default(),
+            std::io::sink,
+            tracer(&provider, "test"),
+        )
+        .unwrap();
+
+        let headers = tracing::subscriber::with_default(subscriber, || {
+            tracing::info_span!("outgoing").in_scope(|| {
+                let mut headers = HeaderMap::new();
+                inject_trace_context(&mut headers);
+                headers
+            })
+        });
+
+        let traceparent = headers.get("traceparent").unwrap().to_str().unwrap();
+        assert!(traceparent.starts_with("00-"));
+    }
+
+    #[test]
+    fn logs_are_copied_to_the_configured_file() {
+        let dir = std::env::temp_dir().join(format!("zero2prod-logs-{}", uuid::Uuid::new_v4()));
+        let path = dir.join("app.log");
+        let settings = LoggingSettings {
+            format: LogFormat::Compact,
+            file: Some(LogFileSettings {
+                path: path.clone(),
+                rotation: LogRotation::Size,
+                max_size_mb: 1,
+                max_files: 2,
+            }),
+            ..Default::default()
+        };
+        let provider = init_tracer("test", &TelemetrySettings::default()).unwrap();
+        let subscriber =
+            get_subscriber("test", &settings, std::io::sink, tracer(&provider, "test")).unwrap();
+
+        tracing::subscriber::with_default(subscriber, || {
+            tracing::info!("written to the file");
+        });
+
+        let logs = std::fs::read_to_string(&path).unwrap();
+        std::fs::remove_dir_all(dir).unwrap();
+        assert!(logs.contains("written to the file"));
+    }
+
+    #[test]
+    fn invalid_filter_directives_are_rejected() {
+        let settings = LoggingSettings {
+            directives: vec!["surrealdb=loud".into()],
+            ..Default::default()
+        };
+        let provider = init_tracer("test", &TelemetrySettings::default()).unwrap();
+
+        let subscriber =
+            get_subscriber("test", &settings, std::io::sink, tracer(&provider, "test"));
+
+        assert!(subscriber.is_err());
+    }
+}
@@ -2,6 +2,20 @@ use unicode_segmentation::UnicodeSegmentation;
 pub fn validate_name(name: &str) -> bool {
     !(contains_forbidden_chars(name) || is_too_long(name) || is_empty_or_whitespace(name))
 }
+/// A light sanity check for addresses from configuration or admin requests:
+/// `local@domain.tld`, no spaces.
+pub fn validate_email(email: &str) -> bool {
+    match email.split_once('@') {
+        Some((local, domain)) => {
+            !local.is_empty()
+                && !domain.contains('@')
+                && domain.split('.').count() > 1
+                && domain.split('.').all(|part| !part.is_empty())
+                && !email.chars().any(char::is_whitespace)
+        }
+        None => false,
+    }
+}
 fn is_too_long(value: &str) -> bool {
     value.graphemes(true).count() > 256
 }
@@ -15,7 +29,7 @@ fn contains_forbidden_chars(value: &str) -> bool {
 
 #[cfg(test)]
 mod tests {
-    use crate::validator::validate_name;
+    use crate::validator::{validate_email, validate_name};
 
     #[test]
     fn too_long_name() {
@@ -42,4 +56,21 @@ mod tests {
         let name = "Ursule LeGuin";
         assert!(validate_name(name))
     }
+    #[test]
+    fn right_email() {
+        assert!(validate_email("ursula@example.com"))
+    }
+    #[test]
+    fn malformed_emails() {
+        for email in [
+            "",
+            "ursula",
+            "@example.com",
+            "ursula@",
+            "ursula@example",
+            "u @example.com",
+        ] {
+            assert!(!validate_email(email), "{email} should be rejected")
+        }
+    }
 }
@@ -0,0 +1,52 @@
+use tokio_util::sync::CancellationToken;
+
+use super::idle;
+use crate::{configuration::SubscriptionSettings, Result, Storage};
+
+/// Only the replica holding this lease cleans up; the others have nothing to add.
+const CLEANUP_LEASE: &str = "cleanup";
+
+/// Removes expired confirmation tokens and subscribers who never confirmed, every
+/// `subscriptions.cleanup_interval_secs`, until `shutdown` is cancelled.
+///
+/// The lease lives as long as the interval, so with several replicas the job runs once per
+/// interval rather than once per replica.
+pub async fn run_cleanup(
+    storage: Storage,
+    settings: SubscriptionSettings,
+    shutdown: CancellationToken,
+) {
+    let interval = settings.cleanup_interval();
+    while !shutdown.is_cancelled() {
+        match storage.acquire_lease(CLEANUP_LEASE, interval).await {
+            Ok(true) => {
+                if let Err(e) = clean_up_subscriptions(&storage, &settings).await {
+                    tracing::error!(error = ?e, "Failed to clean up subscriptions");
+                }
+            }
+            Ok(false) => tracing::debug!("Another replica holds the cleanup lease"),
+            Err(e) => tracing::error!(error = ?e, "Failed to acquire the cleanup lease"),
+        }
+        idle(interval, &shutdown).await;
+    }
+    tracing::info!("Cleanup stopped");
+}
+
+/// Deletes the expired confirmation tokens, then the subscribers still unconfirmed after
+/// the retention period. Returns how many of each were removed.
+#[tracing::instrument(name = "Cleaning up subscriptions", skip_all)]
+pub async fn clean_up_subscriptions(
+    storage: &Storage,
+    settings: &SubscriptionSettings,
+) -> Result<(usize, usize)> {
+    let tokens = storage.delete_expired_tokens().await?;
+    let subscribers = storage
+        .purge_unconfirmed_subscribers(settings.unconfirmed_retention())
+        .await?;
+    if tokens > 0 || subscribers > 0 {
+        tracing::info!(tokens, subscribers, "Removed stale subscriptions");
+    }
+    metrics::counter!("expired_tokens_deleted_total").increment(tokens as u64);
+    metrics::counter!("unconfirmed_subscribers_purged_total").increment(subscribers as u64);
+    Ok((tokens, subscribers))
+}
@@ -0,0 +1,171 @@
+use std::time::Duration;
+
+use tokio_util::sync::CancellationToken;
+
+use super::idle;
+use crate::{
+    delivery::{Delivery, DeliveryOutcome, DeliveryStatus},
+    email_client::INACTIVE_RECIPIENT,
+    issue::IssueStatus,
+    startup::ApplicationBaseUrl,
+    AppError, EmailClient, Result, Storage,
+};
+
+/// How long the worker waits before polling an empty queue again.
+const EMPTY_QUEUE_DELAY: Duration = Duration::from_secs(10);
+/// How long the worker waits after a storage error before retrying.
+const ERROR_DELAY: Duration = Duration::from_secs(1);
+
+pub enum ExecutionOutcome {
+    TaskCompleted,
+    EmptyQueue,
+}
+
+/// How long a claimed delivery stays leased without a heartbeat. It is renewed while the
+/// email is being sent, so this only bounds how long a crashed worker's delivery waits.
+const DELIVERY_LEASE_TTL: Duration = Duration::from_secs(30);
+/// How often the worker refreshes the `delivery_queue_depth` gauge.
+const QUEUE_DEPTH_INTERVAL: Duration = Duration::from_secs(15);
+
+/// Drains the delivery queue until `shutdown` is cancelled.
+///
+/// Cancellation is only checked between deliveries, so a send that has started is always
+/// finished and recorded before the worker returns.
+pub async fn run_delivery_worker(
+    storage: Storage,
+    email_client: EmailClient,
+    base_url: ApplicationBaseUrl,
+    shutdown: CancellationToken,
+) {
+    let mut last_depth_update: Option<tokio::time::Instant> = None;
+    while !shutdown.is_cancelled() {
+        if last_depth_update.is_none_or(|at| at.elapsed() >= QUEUE_DEPTH_INTERVAL) {
+            record_queue_depth(&storage).await;
+            last_depth_update = Some(tokio::time::Instant::now());
+        }
+        match try_execute_task(&storage, &email_client, &base_url).await {
+            Ok(ExecutionOutcome::EmptyQueue) => {
+                metrics::gauge!("delivery_queue_depth").set(0.0);
+                idle(EMPTY_QUEUE_DELAY, &shutdown).await
+            }
+            Ok(ExecutionOutcome::TaskCompleted) => {}
+            Err(e) => {
+                tracing::error!(error = ?e, "Failed to process the delivery queue");
+                idle(ERROR_DELAY, &shutdown).await;
+            }
+        }
+    }
+    tracing::info!("Delivery worker stopped");
+}
+
+async fn record_queue_depth(storage: &Storage) {
+    match storage.count_queued_deliveries().await {
+        Ok(depth) => metrics::gauge!("delivery_queue_depth").set(depth as f64),
+        Err(e) => tracing::warn!(error = ?e, "Failed to count queued deliveries"),
+    }
+}
+
+/// Sends the oldest queued delivery no other worker holds, if any.
+///
+/// The delivery is leased while it is processed, so replicas never send it twice.
+#[tracing::instrument(
+    name = "Processing queued delivery",
+    skip_all,
+    fields(issue_id = tracing::field::Empty, subscriber_id = tracing::field::Empty)
+)]
+pub async fn try_execute_task(
+    storage: &Storage,
+    email_client: &EmailClient,
+    base_url: &ApplicationBaseUrl,
+) -> Result<ExecutionOutcome> {
+    let Some(delivery) = storage.claim_next_delivery(DELIVERY_LEASE_TTL).await? else {
+        return Ok(ExecutionOutcome::EmptyQueue);
+    };
+    let lease = delivery.lease_name();
+    let processed = storage
+        .hold_lease(
+            &lease,
+            DELIVERY_LEASE_TTL,
+            process(storage, email_client, base_url, &delivery),
+        )
+        .await;
+    if let Err(e) = storage.release_lease(&lease).await {
+        tracing::warn!(error = ?e, "Failed to release the delivery lease");
+    }
+    processed?;
+    Ok(ExecutionOutcome::TaskCompleted)
+}
+
+async fn process(
+    storage: &Storage,
+    email_client: &EmailClient,
+    base_url: &ApplicationBaseUrl,
+    delivery: &Delivery,
+) -> Result<()> {
+    let span = tracing::Span::current();
+    span.record("issue_id", tracing::field::display(&delivery.issue));
+    span.record(
+        "subscriber_id",
+        tracing::field::display(&delivery.subscriber),
+    );
+    let outcome = match storage.get_subscriber(&delivery.subscriber).await? {
+        Some(subscriber) => {
+            let issue = storage.get_issue(&delivery.issue.id.to_raw()).await?;
+            if issue.status != IssueStatus::Sending {
+                tracing::info!(status = ?issue.status, "Issue is no longer sending, skipping delivery");
+                return Ok(());
+            }
+            let unsubscribe_url = base_url.unsubscribe_url(&subscriber.id.id.to_raw())?;
+            let content = issue.render_for(&unsubscribe_url)?;
+            let sent = email_client
+                .send_email(
+                    subscriber.email,
+                    &issue.title,
+                    &content.html,
+                    Some(&content.text),
+                )
+                .await;
+            match sent {
+                Ok(message_id) => DeliveryOutcome {
+                    status: DeliveryStatus::Sent,
+                    message_id,
+                    last_error: None,
+                },
+                Err(AppError::EmailRejected {
+                    error_code: Some(INACTIVE_RECIPIENT),
+                    message,
+                    ..
+                }) => {
+                    tracing::warn!("Provider marked the subscriber as inactive");
+                    DeliveryOutcome {
+                        status: DeliveryStatus::Bounced,
+                        message_id: None,
+                        last_error: Some(message),
+                    }
+                }
+                Err(e) => {
+                    tracing::error!(error = ?e, "Failed to deliver issue to a subscriber");
+                    DeliveryOutcome {
+                        status: DeliveryStatus::Failed,
+                        message_id: None,
+                        last_error: Some(format!("{e:?}")),
+                    }
+                }
+            }
+        }
+        None => {
+            tracing::warn!("Subscriber no longer exists, skipping delivery");
+            DeliveryOutcome {
+                status: DeliveryStatus::Suppressed,
+                message_id: None,
+                last_error: None,
+            }
+        }
+    };
+    if !storage.record_delivery_outcome(delivery, outcome).await? {
+        tracing::warn!("Delivery was taken over or cancelled while sending, outcome not recorded");
+        return Ok(());
+    }
+    storage.complete_issue_if_delivered(&delivery.issue).await?;
+    Ok(())
+}
@@ -0,0 +1,18 @@
+use std::time::Duration;
+
+use tokio_util::sync::CancellationToken;
+
+mod cleanup;
+mod delivery;
+mod scheduler;
+pub use cleanup::*;
+pub use delivery::*;
+pub use scheduler::*;
+
+/// Sleeps for `delay`, waking up early when `shutdown` is cancelled.
+async fn idle(delay: Duration, shutdown: &CancellationToken) {
+    tokio::select! {
+        _ = shutdown.cancelled() => {}
+        _ = tokio::time::sleep(delay) => {}
+    }
+}
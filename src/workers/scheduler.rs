@@ -0,0 +1,55 @@
+use std::time::Duration;
+
+use tokio_util::sync::CancellationToken;
+
+use super::idle;
+use crate::{Result, Storage};
+
+/// How often the scheduler looks for issues that are due.
+const POLL_INTERVAL: Duration = Duration::from_secs(10);
+/// Only the replica holding this lease schedules; it keeps it by renewing on every poll.
+const SCHEDULER_LEASE: &str = "scheduler";
+/// Long enough to survive a missed poll, short enough for a quick takeover.
+const SCHEDULER_LEASE_TTL: Duration = Duration::from_secs(30);
+
+/// Enqueues scheduled issues once their send time has passed.
+///
+/// All state lives in the database, so issues that fell due while the process was down are
+/// picked up on the first poll after a restart. With several replicas, only the one holding
+/// the scheduler lease polls; another takes over once it expires. Returns once `shutdown` is
+/// cancelled.
+pub async fn run_scheduler(storage: Storage, shutdown: CancellationToken) {
+    while !shutdown.is_cancelled() {
+        match storage
+            .acquire_lease(SCHEDULER_LEASE, SCHEDULER_LEASE_TTL)
+            .await
+        {
+            Ok(true) => {
+                if let Err(e) = enqueue_due_issues(&storage).await {
+                    tracing::error!(error = ?e, "Failed to enqueue scheduled issues");
+                }
+            }
+            Ok(false) => tracing::debug!("Another replica holds the scheduler lease"),
+            Err(e) => tracing::error!(error = ?e, "Failed to acquire the scheduler lease"),
+        }
+        idle(POLL_INTERVAL, &shutdown).await;
+    }
+    if let Err(e) = storage.release_lease(SCHEDULER_LEASE).await {
+        tracing::warn!(error = ?e, "Failed to release the scheduler lease");
+    }
+    tracing::info!("Scheduler stopped");
+}
+
+/// Enqueues deliveries for every scheduled issue whose send time has passed.
+#[tracing::instrument(name = "Enqueueing due issues", skip_all)]
+pub async fn enqueue_due_issues(storage: &Storage) -> Result<usize> {
+    let mut enqueued = 0;
+    for issue in storage.get_due_issues().await? {
+        if storage.enqueue_issue(&issue.id).await? {
+            tracing::info!(issue_id = %issue.id, "Enqueued scheduled issue");
+            storage.complete_issue_if_delivered(&issue.id).await?;
+            enqueued += 1;
+        }
+    }
+    Ok(enqueued)
+}
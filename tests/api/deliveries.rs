@@ -0,0 +1,202 @@
+use std::time::Duration;
+
+use tokio_util::sync::CancellationToken;
+use wiremock::{matchers::any, Mock, ResponseTemplate};
+use zero2prod::workers::run_delivery_worker;
+
+use crate::helpers::{record_key, spawn_app};
+
+#[tokio::test]
+async fn failed_deliveries_are_reported_and_can_be_requeued() {
+    let test_app = spawn_app().await;
+    test_app
+        .create_confirmed_subscriber("name=le%20guin&email=unlucky_reader%40gmail.com")
+        .await;
+    let subscriber = test_app
+        .db
+        .get_subscriber_by_email("unlucky_reader@gmail.com")
+        .await
+        .unwrap();
+    Mock::given(any())
+        .respond_with(ResponseTemplate::new(500))
+        .mount(&test_app.email_server)
+        .await;
+    let issue: serde_json::Value = test_app
+        .post_newsletters(serde_json::json!({"title": "Outage", "markdown": "Oops"}))
+        .await
+        .json()
+        .await
+        .unwrap();
+    let id = record_key(&issue);
+    test_app.dispatch_all_pending_emails().await;
+
+    let response = test_app.get_issue_deliveries(&id).await;
+    assert_eq!(200, response.status().as_u16());
+    let report: serde_json::Value = response.json().await.unwrap();
+    assert!(report["counts"]["failed"].as_u64().unwrap() >= 1);
+    let failure = report["failures"]
+        .as_array()
+        .unwrap()
+        .iter()
+        .find(|delivery| delivery["subscriber"]["id"]["String"] == subscriber.id.id.to_raw())
+        .expect("The failed delivery is not listed");
+    assert_eq!(failure["status"], "failed");
+    assert!(failure["last_error"].is_string());
+
+    let response = test_app.post_issue_deliveries_requeue(&id).await;
+    test_app.db.delete_subscriber(subscriber).await.unwrap();
+    assert_eq!(200, response.status().as_u16());
+    let requeued: serde_json::Value = response.json().await.unwrap();
+    assert!(requeued["requeued"].as_u64().unwrap() >= 1);
+    let issue: serde_json::Value = test_app.get_issue(&id).await.json().await.unwrap();
+    assert_eq!(issue["status"], "sending");
+}
+#[tokio::test]
+async fn deliveries_of_unknown_issue_return_404() {
+    let test_app = spawn_app().await;
+    let response = test_app.get_issue_deliveries("does-not-exist").await;
+    assert_eq!(404, response.status().as_u16());
+}
+#[tokio::test]
+async fn paused_issues_are_not_delivered_and_can_be_cancelled() {
+    let test_app = spawn_app().await;
+    test_app
+        .create_confirmed_subscriber("name=le%20guin&email=paused_reader%40gmail.com")
+        .await;
+    Mock::given(any())
+        .respond_with(ResponseTemplate::new(200))
+        .mount(&test_app.email_server)
+        .await;
+    let issue: serde_json::Value = test_app
+        .post_newsletters(serde_json::json!({"title": "Typo", "markdown": "Teh news"}))
+        .await
+        .json()
+        .await
+        .unwrap();
+    let id = record_key(&issue);
+
+    let response = test_app.post_issue_action(&id, "pause").await;
+    assert_eq!(200, response.status().as_u16());
+    let issue: serde_json::Value = response.json().await.unwrap();
+    assert_eq!(issue["status"], "paused");
+    test_app.dispatch_all_pending_emails().await;
+    let report: serde_json::Value = test_app
+        .get_issue_deliveries(&id)
+        .await
+        .json()
+        .await
+        .unwrap();
+    assert_eq!(report["counts"]["sent"], 0);
+    assert!(report["counts"]["queued"].as_u64().unwrap() >= 1);
+
+    let response = test_app.post_issue_action(&id, "cancel").await;
+    assert_eq!(200, response.status().as_u16());
+    let issue: serde_json::Value = response.json().await.unwrap();
+    assert_eq!(issue["status"], "cancelled");
+    let report: serde_json::Value = test_app
+        .get_issue_deliveries(&id)
+        .await
+        .json()
+        .await
+        .unwrap();
+    assert_eq!(report["counts"]["queued"], 0);
+    assert!(report["counts"]["cancelled"].as_u64().unwrap() >= 1);
+    let response = test_app.post_issue_action(&id, "resume").await;
+    assert_eq!(409, response.status().as_u16());
+
+    let saved = test_app
+        .db
+        .get_subscriber_by_email("paused_reader@gmail.com")
+        .await
+        .unwrap();
+    test_app.db.delete_subscriber(saved).await.unwrap();
+}
+#[tokio::test]
+async fn paused_issues_can_be_resumed() {
+    let test_app = spawn_app().await;
+    test_app
+        .create_confirmed_subscriber("name=le%20guin&email=resumed_reader%40gmail.com")
+        .await;
+    let issue: serde_json::Value = test_app
+        .post_newsletters(serde_json::json!({"title": "Resumed", "markdown": "Later"}))
+        .await
+        .json()
+        .await
+        .unwrap();
+    let id = record_key(&issue);
+    let paused = test_app.post_issue_action(&id, "pause").await;
+    let resumed = test_app.post_issue_action(&id, "resume").await;
+    test_app.post_issue_action(&id, "cancel").await;
+    let saved = test_app
+        .db
+        .get_subscriber_by_email("resumed_reader@gmail.com")
+        .await
+        .unwrap();
+    test_app.db.delete_subscriber(saved).await.unwrap();
+    assert_eq!(200, paused.status().as_u16());
+    assert_eq!(200, resumed.status().as_u16());
+    let issue: serde_json::Value = resumed.json().await.unwrap();
+    assert_eq!(issue["status"], "sending");
+}
+#[tokio::test]
+async fn delivery_worker_finishes_the_send_in_flight_before_stopping() {
+    let test_app = spawn_app().await;
+    test_app
+        .create_confirmed_subscriber("name=le%20guin&email=last_reader%40gmail.com")
+        .await;
+    let subscriber = test_app
+        .db
+        .get_subscriber_by_email("last_reader@gmail.com")
+        .await
+        .unwrap();
+    Mock::given(any())
+        .respond_with(ResponseTemplate::new(200).set_delay(Duration::from_millis(500)))
+        .mount(&test_app.email_server)
+        .await;
+    let issue: serde_json::Value = test_app
+        .post_newsletters(serde_json::json!({"title": "Goodbye", "markdown": "For now"}))
+        .await
+        .json()
+        .await
+        .unwrap();
+    let id = record_key(&issue);
+    let shutdown = CancellationToken::new();
+    let worker = tokio::spawn(run_delivery_worker(
+        test_app.db.clone(),
+        test_app.email_client.clone(),
+        test_app.base_url.clone(),
+        shutdown.clone(),
+    ));
+    while test_app.last_email().await["Subject"] != "Goodbye" {
+        tokio::time::sleep(Duration::from_millis(10)).await;
+    }
+
+    shutdown.cancel();
+    tokio::time::timeout(Duration::from_secs(5), worker)
+        .await
+        .expect("The worker did not stop")
+        .unwrap();
+
+    test_app.db.delete_subscriber(subscriber).await.unwrap();
+    // The queue is shared with other tests, so only check that every send this worker
+    // started, the one in flight at cancellation included, has been recorded.
+    let started = test_app
+        .email_server
+        .received_requests()
+        .await
+        .unwrap()
+        .iter()
+        .filter(|request| {
+            serde_json::from_slice::<serde_json::Value>(&request.body)
+                .is_ok_and(|body| body["Subject"] == "Goodbye")
+        })
+        .count() as u64;
+    let report: serde_json::Value = test_app
+        .get_issue_deliveries(&id)
+        .await
+        .json()
+        .await
+        .unwrap();
+    assert!(started >= 1);
+    assert!(report["counts"]["sent"].as_u64().unwrap() >= started);
+}
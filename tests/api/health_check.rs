@@ -1,4 +1,4 @@
-use crate::helpers::{spawn_app, APP_USER_AGENT};
+use crate::helpers::{api_client, spawn_app, APP_USER_AGENT};
 
 #[tokio::test]
 async fn health_check_works() {
@@ -8,10 +8,32 @@ async fn health_check_works() {
         .build()
         .unwrap();
     let response = client
-        .get(&format!("{address}/health_check"))
+        .get(format!("{address}/health_check"))
         .send()
         .await
         .expect("Failed to execute request.");
     assert!(response.status().is_success());
-    assert_eq!(Some(0), response.content_length());
+    let report: serde_json::Value = response.json().await.unwrap();
+    assert_eq!(report["status"], "ok");
+    assert_eq!(report["email_providers"][0]["name"], "primary");
+    assert_eq!(report["email_providers"][0]["circuit"], "closed");
+}
+
+#[tokio::test]
+async fn readiness_reports_every_dependency() {
+    let app = spawn_app().await;
+
+    let response = api_client()
+        .get(format!("{}/health/ready", &app.address))
+        .send()
+        .await
+        .expect("Failed to execute request.");
+
+    assert_eq!(response.status().as_u16(), 200);
+    let report: serde_json::Value = response.json().await.unwrap();
+    assert_eq!(report["status"], "ready");
+    assert_eq!(report["dependencies"]["database"]["status"], "up");
+    assert_eq!(report["dependencies"]["database"]["critical"], true);
+    assert!(report["dependencies"]["database"]["latency_ms"].is_number());
+    assert_eq!(report["dependencies"]["email"]["status"], "up");
 }
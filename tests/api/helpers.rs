@@ -1,53 +1,349 @@
+use metrics_exporter_prometheus::PrometheusHandle;
 use once_cell::sync::Lazy;
+use secrecy::ExposeSecret;
 use tokio::net::TcpListener;
+use tokio_util::sync::CancellationToken;
+use wiremock::{
+    matchers::{method, path},
+    Mock, MockServer, ResponseTemplate,
+};
 use zero2prod::{
-    configuration::get_configuration,
-    startup::app,
-    telemetry::{get_subscriber, init_subscriber},
+    authentication::AdminToken,
+    configuration::{get_configuration, Settings},
+    startup::{app, metrics_app, ApplicationBaseUrl},
+    telemetry::{get_subscriber, init_metrics, init_subscriber, init_tracer, tracer},
+    workers::{try_execute_task, ExecutionOutcome},
     EmailClient, Storage,
 };
 pub static APP_USER_AGENT: &str = concat!(env!("CARGO_PKG_NAME"), "/", env!("CARGO_PKG_VERSION"));
 
 // Ensure that the `tracing` stack is only initialised once using `once_cell`
 pub static TRACING: Lazy<()> = Lazy::new(|| {
+    let provider = init_tracer("test", &Default::default()).expect("Failed to build the tracer");
+    let tracer = tracer(&provider, "test");
     if std::env::var("TEST_LOG").is_ok() {
-        let subscriber = get_subscriber("test", "info", std::io::stdout);
+        let subscriber =
+            get_subscriber("test", &Default::default(), std::io::stdout, tracer).unwrap();
         init_subscriber(subscriber);
     } else {
-        let subscriber = get_subscriber("test", "info", std::io::sink);
+        let subscriber =
+            get_subscriber("test", &Default::default(), std::io::sink, tracer).unwrap();
         init_subscriber(subscriber);
     };
 });
+// The Prometheus recorder is global, so it is installed once and shared by every test
+pub static METRICS: Lazy<PrometheusHandle> =
+    Lazy::new(|| init_metrics().expect("Failed to install the metrics recorder"));
 pub struct TestApp {
     pub address: String,
+    pub metrics_address: String,
     pub db: Storage,
+    pub email_server: MockServer,
+    pub email_client: EmailClient,
+    pub base_url: ApplicationBaseUrl,
+    pub admin_token: String,
 }
 impl TestApp {
+    pub async fn dispatch_all_pending_emails(&self) {
+        loop {
+            if let ExecutionOutcome::EmptyQueue =
+                try_execute_task(&self.db, &self.email_client, &self.base_url)
+                    .await
+                    .unwrap()
+            {
+                break;
+            }
+        }
+    }
+    pub async fn get_metrics(&self) -> reqwest::Response {
+        api_client()
+            .get(format!("{}/metrics", &self.metrics_address))
+            .send()
+            .await
+            .expect("Failed to execute request.")
+    }
     pub async fn post_subscriptions(&self, body: &'static str) -> reqwest::Response {
-        let client = reqwest::Client::builder()
-            .user_agent(APP_USER_AGENT)
-            .build()
-            .unwrap();
-        client
-            .post(&format!("{}/subscriptions", &self.address))
+        api_client()
+            .post(format!("{}/subscriptions", &self.address))
             .header("Content-Type", "application/x-www-form-urlencoded")
             .body(body)
             .send()
             .await
             .expect("Failed to execute request.")
     }
+    /// Subscribes and follows the link in the confirmation email, so that the subscriber
+    /// receives issues.
+    pub async fn create_confirmed_subscriber(&self, body: &'static str) {
+        let _mock_guard = Mock::given(path("/email"))
+            .and(method("POST"))
+            .respond_with(ResponseTemplate::new(200))
+            .named("Confirmation email")
+            .expect(1)
+            .mount_as_scoped(&self.email_server)
+            .await;
+        self.post_subscriptions(body)
+            .await
+            .error_for_status()
+            .unwrap();
+        let email = self.last_email().await;
+        self.get_confirmation(&confirmation_token(&email))
+            .await
+            .error_for_status()
+            .unwrap();
+    }
+    /// The body of the last email sent through the mock provider.
+    pub async fn last_email(&self) -> serde_json::Value {
+        let request = self
+            .email_server
+            .received_requests()
+            .await
+            .unwrap()
+            .pop()
+            .expect("No email was sent");
+        serde_json::from_slice(&request.body).unwrap()
+    }
+    pub async fn get_confirmation(&self, token: &str) -> reqwest::Response {
+        api_client()
+            .get(format!("{}/subscriptions/confirm", &self.address))
+            .query(&[("subscription_token", token)])
+            .send()
+            .await
+            .expect("Failed to execute request.")
+    }
+    /// Follows the unsubscribe link an email would carry for `subscriber_key`.
+    pub async fn get_unsubscribe(&self, subscriber_key: &str) -> reqwest::Response {
+        let link =
+            reqwest::Url::parse(&self.base_url.unsubscribe_url(subscriber_key).unwrap()).unwrap();
+        api_client()
+            .get(format!("{}/subscriptions/unsubscribe", &self.address))
+            .query(&link.query_pairs().collect::<Vec<_>>())
+            .send()
+            .await
+            .expect("Failed to execute request.")
+    }
+    /// Submits the unsubscribe form with the signature from the link for `signed_key`.
+    pub async fn post_unsubscribe(
+        &self,
+        subscriber_key: &str,
+        signed_key: &str,
+    ) -> reqwest::Response {
+        let link =
+            reqwest::Url::parse(&self.base_url.unsubscribe_url(signed_key).unwrap()).unwrap();
+        let signature = link
+            .query_pairs()
+            .find(|(name, _)| name == "signature")
+            .unwrap()
+            .1
+            .into_owned();
+        api_client()
+            .post(format!("{}/subscriptions/unsubscribe", &self.address))
+            .form(&[("subscriber", subscriber_key), ("signature", &signature)])
+            .send()
+            .await
+            .expect("Failed to execute request.")
+    }
+    pub async fn post_newsletters(&self, body: serde_json::Value) -> reqwest::Response {
+        api_client()
+            .post(format!("{}/newsletters", &self.address))
+            .bearer_auth(&self.admin_token)
+            .json(&body)
+            .send()
+            .await
+            .expect("Failed to execute request.")
+    }
+    pub async fn post_issues(&self, body: serde_json::Value) -> reqwest::Response {
+        api_client()
+            .post(format!("{}/admin/issues", &self.address))
+            .bearer_auth(&self.admin_token)
+            .json(&body)
+            .send()
+            .await
+            .expect("Failed to execute request.")
+    }
+    pub async fn get_issues(&self) -> reqwest::Response {
+        api_client()
+            .get(format!("{}/admin/issues", &self.address))
+            .bearer_auth(&self.admin_token)
+            .send()
+            .await
+            .expect("Failed to execute request.")
+    }
+    pub async fn get_issue(&self, id: &str) -> reqwest::Response {
+        api_client()
+            .get(format!("{}/admin/issues/{id}", &self.address))
+            .bearer_auth(&self.admin_token)
+            .send()
+            .await
+            .expect("Failed to execute request.")
+    }
+    pub async fn put_issue(&self, id: &str, body: serde_json::Value) -> reqwest::Response {
+        api_client()
+            .put(format!("{}/admin/issues/{id}", &self.address))
+            .bearer_auth(&self.admin_token)
+            .json(&body)
+            .send()
+            .await
+            .expect("Failed to execute request.")
+    }
+    pub async fn delete_issue(&self, id: &str) -> reqwest::Response {
+        api_client()
+            .delete(format!("{}/admin/issues/{id}", &self.address))
+            .bearer_auth(&self.admin_token)
+            .send()
+            .await
+            .expect("Failed to execute request.")
+    }
+    pub async fn post_issue_schedule(
+        &self,
+        id: &str,
+        body: serde_json::Value,
+    ) -> reqwest::Response {
+        api_client()
+            .post(format!("{}/admin/issues/{id}/schedule", &self.address))
+            .bearer_auth(&self.admin_token)
+            .json(&body)
+            .send()
+            .await
+            .expect("Failed to execute request.")
+    }
+    pub async fn delete_issue_schedule(&self, id: &str) -> reqwest::Response {
+        api_client()
+            .delete(format!("{}/admin/issues/{id}/schedule", &self.address))
+            .bearer_auth(&self.admin_token)
+            .send()
+            .await
+            .expect("Failed to execute request.")
+    }
+    pub async fn post_issue_test_send(
+        &self,
+        id: &str,
+        body: serde_json::Value,
+    ) -> reqwest::Response {
+        api_client()
+            .post(format!("{}/admin/issues/{id}/test-send", &self.address))
+            .bearer_auth(&self.admin_token)
+            .json(&body)
+            .send()
+            .await
+            .expect("Failed to execute request.")
+    }
+    pub async fn post_issue_action(&self, id: &str, action: &str) -> reqwest::Response {
+        api_client()
+            .post(format!("{}/admin/issues/{id}/{action}", &self.address))
+            .bearer_auth(&self.admin_token)
+            .send()
+            .await
+            .expect("Failed to execute request.")
+    }
+    pub async fn get_issue_deliveries(&self, id: &str) -> reqwest::Response {
+        api_client()
+            .get(format!("{}/admin/issues/{id}/deliveries", &self.address))
+            .bearer_auth(&self.admin_token)
+            .send()
+            .await
+            .expect("Failed to execute request.")
+    }
+    pub async fn post_issue_deliveries_requeue(&self, id: &str) -> reqwest::Response {
+        api_client()
+            .post(format!(
+                "{}/admin/issues/{id}/deliveries/requeue",
+                &self.address
+            ))
+            .bearer_auth(&self.admin_token)
+            .send()
+            .await
+            .expect("Failed to execute request.")
+    }
+    pub async fn get_issue_preview(&self, id: &str) -> reqwest::Response {
+        api_client()
+            .get(format!("{}/admin/issues/{id}/preview", &self.address))
+            .bearer_auth(&self.admin_token)
+            .send()
+            .await
+            .expect("Failed to execute request.")
+    }
+}
+pub fn api_client() -> reqwest::Client {
+    reqwest::Client::builder()
+        .user_agent(APP_USER_AGENT)
+        .build()
+        .unwrap()
+}
+/// Extracts the token from the link in a confirmation email.
+pub fn confirmation_token(email: &serde_json::Value) -> String {
+    let text = email["TextBody"].as_str().unwrap();
+    let (_, rest) = text
+        .split_once("subscription_token=")
+        .expect("No confirmation link in the email");
+    rest.split(|c: char| !c.is_ascii_alphanumeric())
+        .next()
+        .unwrap()
+        .to_string()
+}
+/// Extracts the record key from a serialized SurrealDB `Thing`.
+pub fn record_key(record: &serde_json::Value) -> String {
+    record["id"]["id"]["String"]
+        .as_str()
+        .expect("Record id is not a string")
+        .to_string()
 }
 pub async fn spawn_app() -> TestApp {
+    spawn_app_with(|_| {}).await
+}
+/// Like [`spawn_app`], with `configure` applied to the settings first.
+pub async fn spawn_app_with(configure: impl FnOnce(&mut Settings)) -> TestApp {
     Lazy::force(&TRACING);
     let listener = TcpListener::bind("0.0.0.0:0")
         .await
         .expect("Failed to bind random port");
     let port = listener.local_addr().unwrap().port();
-    let configuration = get_configuration().unwrap();
-    let mail = EmailClient::new(&configuration).unwrap();
-    let db = Storage::init(configuration).await.unwrap();
-    let app = app(db.clone(), mail);
-    tokio::spawn(zero2prod::startup::run(listener, app));
+    let email_server = MockServer::start().await;
+    let mut configuration = get_configuration().unwrap();
+    configuration.email_client.base_url = email_server.uri();
+    configure(&mut configuration);
+    let mail = EmailClient::new(&configuration.email_client).unwrap();
+    let base_url = ApplicationBaseUrl::new(
+        configuration.application.base_url.clone(),
+        configuration.application.hmac_secret.clone(),
+    );
+    let db = Storage::init(configuration.database.clone()).await.unwrap();
+    let app = app(
+        db.clone(),
+        mail.clone(),
+        base_url.clone(),
+        AdminToken::new(configuration.application.admin_token.clone()),
+        &configuration.application.timeouts,
+        &configuration.subscriptions,
+    );
+    tokio::spawn(zero2prod::startup::run(
+        listener,
+        app,
+        CancellationToken::new(),
+    ));
+    let metrics_listener = TcpListener::bind("0.0.0.0:0")
+        .await
+        .expect("Failed to bind random port");
+    let metrics_address = format!(
+        "http://0.0.0.0:{}",
+        metrics_listener.local_addr().unwrap().port()
+    );
+    tokio::spawn(zero2prod::startup::run(
+        metrics_listener,
+        metrics_app(METRICS.clone()),
+        CancellationToken::new(),
+    ));
     let address = format!("http://0.0.0.0:{}", port);
-    TestApp { address, db }
+    TestApp {
+        address,
+        metrics_address,
+        db,
+        email_server,
+        email_client: mail,
+        base_url,
+        admin_token: configuration
+            .application
+            .admin_token
+            .expose_secret()
+            .clone(),
+    }
 }
@@ -0,0 +1,277 @@
+use wiremock::{
+    matchers::{any, body_partial_json, header_regex},
+    Mock, Request, ResponseTemplate,
+};
+
+use crate::helpers::{api_client, record_key, spawn_app, spawn_app_with};
+use zero2prod::issue::MAX_TEST_RECIPIENTS;
+
+#[tokio::test]
+async fn admin_routes_reject_requests_without_the_admin_token() {
+    let test_app = spawn_app().await;
+    let requests = [
+        api_client().get(format!("{}/admin/issues", &test_app.address)),
+        api_client()
+            .post(format!("{}/admin/issues", &test_app.address))
+            .json(&serde_json::json!({"title": "Sneaky", "markdown": "Hi"})),
+        api_client()
+            .get(format!("{}/admin/issues", &test_app.address))
+            .bearer_auth("not-the-admin-token"),
+    ];
+
+    for request in requests {
+        let response = request.send().await.expect("Failed to execute request.");
+        assert_eq!(401, response.status().as_u16());
+        assert_eq!(response.headers()["www-authenticate"], "Bearer");
+    }
+    let issues: Vec<serde_json::Value> = test_app.get_issues().await.json().await.unwrap();
+    assert!(issues.iter().all(|issue| issue["title"] != "Sneaky"));
+}
+
+#[tokio::test]
+async fn drafts_can_be_created_edited_listed_and_deleted() {
+    let test_app = spawn_app().await;
+    let response = test_app
+        .post_issues(serde_json::json!({"title": "Draft", "markdown": "First take"}))
+        .await;
+    assert_eq!(200, response.status().as_u16());
+    let draft: serde_json::Value = response.json().await.unwrap();
+    assert_eq!(draft["status"], "draft");
+    let id = record_key(&draft);
+
+    let response = test_app
+        .put_issue(
+            &id,
+            serde_json::json!({"title": "Edited draft", "markdown": "Second take"}),
+        )
+        .await;
+    assert_eq!(200, response.status().as_u16());
+    let edited: serde_json::Value = test_app.get_issue(&id).await.json().await.unwrap();
+    assert_eq!(edited["title"], "Edited draft");
+    assert_eq!(edited["markdown"], "Second take");
+
+    let issues: Vec<serde_json::Value> = test_app.get_issues().await.json().await.unwrap();
+    assert!(issues.iter().any(|issue| record_key(issue) == id));
+
+    let response = test_app.delete_issue(&id).await;
+    assert_eq!(204, response.status().as_u16());
+    assert_eq!(404, test_app.get_issue(&id).await.status().as_u16());
+}
+#[tokio::test]
+async fn preview_renders_html_without_sending_emails() {
+    let test_app = spawn_app().await;
+    Mock::given(any())
+        .respond_with(ResponseTemplate::new(200))
+        .expect(0)
+        .mount(&test_app.email_server)
+        .await;
+    let draft: serde_json::Value = test_app
+        .post_issues(serde_json::json!({"title": "Preview", "markdown": "# Big news"}))
+        .await
+        .json()
+        .await
+        .unwrap();
+    let id = record_key(&draft);
+    let response = test_app.get_issue_preview(&id).await;
+    test_app.delete_issue(&id).await;
+    assert_eq!(200, response.status().as_u16());
+    assert!(response.headers()["content-type"]
+        .to_str()
+        .unwrap()
+        .starts_with("text/html"));
+    let html = response.text().await.unwrap();
+    assert!(html.contains("Big news"));
+}
+#[tokio::test]
+async fn sent_issues_cannot_be_edited_or_deleted() {
+    let test_app = spawn_app().await;
+    Mock::given(any())
+        .respond_with(ResponseTemplate::new(200))
+        .mount(&test_app.email_server)
+        .await;
+    let sent: serde_json::Value = test_app
+        .post_newsletters(serde_json::json!({"title": "Sent", "markdown": "Already out"}))
+        .await
+        .json()
+        .await
+        .unwrap();
+    let id = record_key(&sent);
+    let response = test_app
+        .put_issue(
+            &id,
+            serde_json::json!({"title": "Too late", "markdown": "Nope"}),
+        )
+        .await;
+    assert_eq!(409, response.status().as_u16());
+    assert_eq!(409, test_app.delete_issue(&id).await.status().as_u16());
+}
+#[tokio::test]
+async fn unknown_issue_returns_404() {
+    let test_app = spawn_app().await;
+    let response = test_app.get_issue("does-not-exist").await;
+    assert_eq!(404, response.status().as_u16());
+}
+#[tokio::test]
+async fn test_send_goes_only_to_the_given_recipients() {
+    let test_app = spawn_app().await;
+    let has_fake_unsubscribe_link = |request: &Request| {
+        serde_json::from_slice::<serde_json::Value>(&request.body).is_ok_and(|body| {
+            body["HtmlBody"]
+                .as_str()
+                .is_some_and(|html| html.contains("subscriber=test-recipient"))
+        })
+    };
+    for recipient in ["first@example.com", "second@example.com"] {
+        Mock::given(body_partial_json(serde_json::json!({ "To": recipient })))
+            .and(has_fake_unsubscribe_link)
+            .respond_with(ResponseTemplate::new(200))
+            .expect(1)
+            .mount(&test_app.email_server)
+            .await;
+    }
+    let draft: serde_json::Value = test_app
+        .post_issues(serde_json::json!({"title": "Test send", "markdown": "Proofread me"}))
+        .await
+        .json()
+        .await
+        .unwrap();
+    let id = record_key(&draft);
+    let response = test_app
+        .post_issue_test_send(
+            &id,
+            serde_json::json!({"recipients": ["first@example.com", "second@example.com"]}),
+        )
+        .await;
+    test_app.delete_issue(&id).await;
+    assert_eq!(200, response.status().as_u16());
+    let report: serde_json::Value = response.json().await.unwrap();
+    assert_eq!(report["sent"].as_array().unwrap().len(), 2);
+    assert!(report["failed"].as_array().unwrap().is_empty());
+}
+#[tokio::test]
+async fn test_send_propagates_the_incoming_trace_context() {
+    let test_app = spawn_app().await;
+    let trace_id = "0af7651916cd43dd8448eb211c80319c";
+    Mock::given(header_regex("traceparent", &format!("^00-{trace_id}-")))
+        .respond_with(ResponseTemplate::new(200))
+        .expect(1)
+        .mount(&test_app.email_server)
+        .await;
+    let draft: serde_json::Value = test_app
+        .post_issues(serde_json::json!({"title": "Traced", "markdown": "Follow me"}))
+        .await
+        .json()
+        .await
+        .unwrap();
+    let id = record_key(&draft);
+    let response = api_client()
+        .post(format!("{}/admin/issues/{id}/test-send", &test_app.address))
+        .bearer_auth(&test_app.admin_token)
+        .header("traceparent", format!("00-{trace_id}-b7ad6b7169203331-01"))
+        .json(&serde_json::json!({"recipients": ["traced@example.com"]}))
+        .send()
+        .await
+        .expect("Failed to execute request.");
+    test_app.delete_issue(&id).await;
+    assert_eq!(200, response.status().as_u16());
+}
+#[tokio::test]
+async fn test_send_without_recipients_returns_400() {
+    let test_app = spawn_app().await;
+    Mock::given(any())
+        .respond_with(ResponseTemplate::new(200))
+        .expect(0)
+        .mount(&test_app.email_server)
+        .await;
+    let draft: serde_json::Value = test_app
+        .post_issues(serde_json::json!({"title": "Test send", "markdown": "Nobody"}))
+        .await
+        .json()
+        .await
+        .unwrap();
+    let id = record_key(&draft);
+    let response = test_app
+        .post_issue_test_send(&id, serde_json::json!({"recipients": []}))
+        .await;
+    test_app.delete_issue(&id).await;
+    assert_eq!(400, response.status().as_u16());
+}
+#[tokio::test]
+async fn test_send_with_a_malformed_recipient_returns_400_and_sends_nothing() {
+    let test_app = spawn_app().await;
+    Mock::given(any())
+        .respond_with(ResponseTemplate::new(200))
+        .expect(0)
+        .mount(&test_app.email_server)
+        .await;
+    let draft: serde_json::Value = test_app
+        .post_issues(serde_json::json!({"title": "Test send", "markdown": "Typo"}))
+        .await
+        .json()
+        .await
+        .unwrap();
+    let id = record_key(&draft);
+    let response = test_app
+        .post_issue_test_send(
+            &id,
+            serde_json::json!({"recipients": ["first@example.com", "second.example.com"]}),
+        )
+        .await;
+    test_app.delete_issue(&id).await;
+    assert_eq!(400, response.status().as_u16());
+    let body: serde_json::Value = response.json().await.unwrap();
+    assert_eq!(body["error"], "Invalid recipient: second.example.com");
+}
+#[tokio::test]
+async fn test_send_to_too_many_recipients_returns_400_and_sends_nothing() {
+    let test_app = spawn_app().await;
+    Mock::given(any())
+        .respond_with(ResponseTemplate::new(200))
+        .expect(0)
+        .mount(&test_app.email_server)
+        .await;
+    let draft: serde_json::Value = test_app
+        .post_issues(serde_json::json!({"title": "Test send", "markdown": "Everyone"}))
+        .await
+        .json()
+        .await
+        .unwrap();
+    let id = record_key(&draft);
+    let recipients: Vec<_> = (0..=MAX_TEST_RECIPIENTS)
+        .map(|i| format!("reader{i}@example.com"))
+        .collect();
+    let response = test_app
+        .post_issue_test_send(&id, serde_json::json!({ "recipients": recipients }))
+        .await;
+    test_app.delete_issue(&id).await;
+    assert_eq!(400, response.status().as_u16());
+}
+#[tokio::test]
+async fn test_send_outside_the_allowed_domains_returns_400_and_sends_nothing() {
+    let test_app = spawn_app_with(|settings| {
+        settings.email_client.test_send_domains = vec!["example.com".into()];
+    })
+    .await;
+    Mock::given(any())
+        .respond_with(ResponseTemplate::new(200))
+        .expect(0)
+        .mount(&test_app.email_server)
+        .await;
+    let draft: serde_json::Value = test_app
+        .post_issues(serde_json::json!({"title": "Test send", "markdown": "Outsider"}))
+        .await
+        .json()
+        .await
+        .unwrap();
+    let id = record_key(&draft);
+    let response = test_app
+        .post_issue_test_send(
+            &id,
+            serde_json::json!({"recipients": ["editor@example.com", "stranger@gmail.com"]}),
+        )
+        .await;
+    test_app.delete_issue(&id).await;
+    assert_eq!(400, response.status().as_u16());
+    let body: serde_json::Value = response.json().await.unwrap();
+    assert_eq!(body["error"], "Invalid recipient: stranger@gmail.com");
+}
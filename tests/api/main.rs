@@ -1,3 +1,9 @@
-mod helpers;
+mod deliveries;
 mod health_check;
+mod helpers;
+mod issues;
+mod metrics;
+mod newsletters;
+mod request_ids;
+mod scheduling;
 mod subscriptions;
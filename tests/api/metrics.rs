@@ -0,0 +1,32 @@
+use crate::helpers::{api_client, spawn_app};
+
+#[tokio::test]
+async fn metrics_are_served_in_prometheus_format() {
+    let app = spawn_app().await;
+    api_client()
+        .get(format!("{}/health_check", &app.address))
+        .send()
+        .await
+        .expect("Failed to execute request.");
+
+    let response = app.get_metrics().await;
+
+    assert!(response.status().is_success());
+    let body = response.text().await.unwrap();
+    assert!(body.contains("http_requests_total"));
+    assert!(body.contains(r#"route="/health_check""#));
+    assert!(body.contains("http_request_duration_seconds_bucket"));
+}
+
+#[tokio::test]
+async fn metrics_are_not_served_on_the_application_port() {
+    let app = spawn_app().await;
+
+    let response = api_client()
+        .get(format!("{}/metrics", &app.address))
+        .send()
+        .await
+        .expect("Failed to execute request.");
+
+    assert_eq!(response.status().as_u16(), 404);
+}
@@ -0,0 +1,91 @@
+use wiremock::{
+    matchers::{any, method, path},
+    Mock, ResponseTemplate,
+};
+
+use crate::helpers::{api_client, spawn_app};
+
+#[tokio::test]
+async fn newsletters_are_delivered_to_subscribers() {
+    let test_app = spawn_app().await;
+    let body = "name=le%20guin&email=newsletter_reader%40gmail.com";
+    test_app.create_confirmed_subscriber(body).await;
+    Mock::given(path("/email"))
+        .and(method("POST"))
+        .respond_with(ResponseTemplate::new(200))
+        .expect(1..)
+        .mount(&test_app.email_server)
+        .await;
+    let newsletter = serde_json::json!({
+        "title": "Newsletter title",
+        "markdown": "# Hello\n\nNewsletter body as **Markdown**.",
+    });
+    let response = test_app.post_newsletters(newsletter).await;
+    test_app.dispatch_all_pending_emails().await;
+    let saved = test_app
+        .db
+        .get_subscriber_by_email("newsletter_reader@gmail.com")
+        .await
+        .unwrap();
+    test_app.db.delete_subscriber(saved).await.unwrap();
+    assert_eq!(200, response.status().as_u16());
+    let issue: serde_json::Value = response.json().await.unwrap();
+    assert_eq!(
+        issue["markdown"],
+        "# Hello\n\nNewsletter body as **Markdown**."
+    );
+}
+#[tokio::test]
+async fn newsletters_returns_400_for_invalid_data() {
+    let test_app = spawn_app().await;
+    Mock::given(any())
+        .respond_with(ResponseTemplate::new(200))
+        .expect(0)
+        .mount(&test_app.email_server)
+        .await;
+    let test_cases = vec![
+        (
+            serde_json::json!({"markdown": "Newsletter body"}),
+            422,
+            "missing title",
+        ),
+        (
+            serde_json::json!({"title": "Newsletter!"}),
+            422,
+            "missing content",
+        ),
+        (
+            serde_json::json!({"title": " ", "markdown": "Newsletter body"}),
+            400,
+            "blank title",
+        ),
+    ];
+    for (invalid_body, status, error_message) in test_cases {
+        let response = test_app.post_newsletters(invalid_body).await;
+        assert_eq!(
+            status,
+            response.status().as_u16(),
+            "The API did not fail with {} when the payload was {}.",
+            status,
+            error_message
+        );
+    }
+}
+#[tokio::test]
+async fn newsletters_cannot_be_published_without_the_admin_token() {
+    let test_app = spawn_app().await;
+    Mock::given(any())
+        .respond_with(ResponseTemplate::new(200))
+        .expect(0)
+        .mount(&test_app.email_server)
+        .await;
+
+    let response = api_client()
+        .post(format!("{}/newsletters", &test_app.address))
+        .json(&serde_json::json!({"title": "Spam", "markdown": "Buy now"}))
+        .send()
+        .await
+        .expect("Failed to execute request.");
+
+    assert_eq!(401, response.status().as_u16());
+}
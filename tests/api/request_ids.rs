@@ -0,0 +1,79 @@
+use wiremock::{matchers::header, Mock, ResponseTemplate};
+
+use crate::helpers::{api_client, record_key, spawn_app};
+
+#[tokio::test]
+async fn a_well_formed_request_id_is_echoed_back() {
+    let app = spawn_app().await;
+
+    let response = api_client()
+        .get(format!("{}/health_check", &app.address))
+        .header("x-request-id", "edge-7f3c:42")
+        .send()
+        .await
+        .expect("Failed to execute request.");
+
+    assert_eq!(response.headers()["x-request-id"], "edge-7f3c:42");
+}
+
+#[tokio::test]
+async fn a_malformed_request_id_is_replaced_with_a_uuid() {
+    let app = spawn_app().await;
+
+    let response = api_client()
+        .get(format!("{}/health_check", &app.address))
+        .header("x-request-id", "not a valid id")
+        .send()
+        .await
+        .expect("Failed to execute request.");
+
+    let id = response.headers()["x-request-id"].to_str().unwrap();
+    assert!(uuid::Uuid::parse_str(id).is_ok());
+}
+
+#[tokio::test]
+async fn error_bodies_carry_the_request_id() {
+    let app = spawn_app().await;
+
+    let response = api_client()
+        .get(format!("{}/admin/issues/missing", &app.address))
+        .bearer_auth(&app.admin_token)
+        .header("x-request-id", "trace-me")
+        .send()
+        .await
+        .expect("Failed to execute request.");
+
+    assert_eq!(404, response.status().as_u16());
+    let body: serde_json::Value = response.json().await.unwrap();
+    assert_eq!(body["error"], "Issue not found");
+    assert_eq!(body["request_id"], "trace-me");
+}
+
+#[tokio::test]
+async fn the_request_id_is_forwarded_to_the_email_provider() {
+    let app = spawn_app().await;
+    Mock::given(header("x-request-id", "send-123"))
+        .respond_with(ResponseTemplate::new(200))
+        .expect(1)
+        .mount(&app.email_server)
+        .await;
+    let draft: serde_json::Value = app
+        .post_issues(serde_json::json!({"title": "Correlated", "markdown": "Hello"}))
+        .await
+        .json()
+        .await
+        .unwrap();
+    let id = record_key(&draft);
+
+    let response = api_client()
+        .post(format!("{}/admin/issues/{id}/test-send", &app.address))
+        .bearer_auth(&app.admin_token)
+        .header("x-request-id", "send-123")
+        .json(&serde_json::json!({"recipients": ["someone@example.com"]}))
+        .send()
+        .await
+        .expect("Failed to execute request.");
+    app.delete_issue(&id).await;
+
+    assert_eq!(200, response.status().as_u16());
+}
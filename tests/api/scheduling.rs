@@ -0,0 +1,91 @@
+use chrono::{Duration, Utc};
+use surrealdb::sql::Datetime;
+use wiremock::{
+    matchers::{method, path},
+    Mock, ResponseTemplate,
+};
+use zero2prod::{issue::IssueStatus, workers::enqueue_due_issues};
+
+use crate::helpers::{record_key, spawn_app, TestApp};
+
+async fn create_draft(test_app: &TestApp) -> String {
+    let draft: serde_json::Value = test_app
+        .post_issues(serde_json::json!({"title": "Scheduled", "markdown": "Coming soon"}))
+        .await
+        .json()
+        .await
+        .unwrap();
+    record_key(&draft)
+}
+#[tokio::test]
+async fn drafts_can_be_scheduled_rescheduled_and_cancelled() {
+    let test_app = spawn_app().await;
+    let id = create_draft(&test_app).await;
+
+    let send_at = Utc::now() + Duration::hours(1);
+    let response = test_app
+        .post_issue_schedule(&id, serde_json::json!({ "send_at": send_at }))
+        .await;
+    assert_eq!(200, response.status().as_u16());
+    let issue: serde_json::Value = response.json().await.unwrap();
+    assert_eq!(issue["status"], "scheduled");
+
+    let send_at = Utc::now() + Duration::hours(2);
+    let response = test_app
+        .post_issue_schedule(&id, serde_json::json!({ "send_at": send_at }))
+        .await;
+    assert_eq!(200, response.status().as_u16());
+
+    let response = test_app.delete_issue_schedule(&id).await;
+    assert_eq!(200, response.status().as_u16());
+    let issue: serde_json::Value = response.json().await.unwrap();
+    assert_eq!(issue["status"], "draft");
+    assert!(issue["send_at"].is_null());
+    test_app.delete_issue(&id).await;
+}
+#[tokio::test]
+async fn scheduling_in_the_past_is_rejected() {
+    let test_app = spawn_app().await;
+    let id = create_draft(&test_app).await;
+    let send_at = Utc::now() - Duration::minutes(1);
+    let response = test_app
+        .post_issue_schedule(&id, serde_json::json!({ "send_at": send_at }))
+        .await;
+    test_app.delete_issue(&id).await;
+    assert_eq!(400, response.status().as_u16());
+}
+#[tokio::test]
+async fn due_issues_are_enqueued_and_delivered() {
+    let test_app = spawn_app().await;
+    test_app
+        .create_confirmed_subscriber("name=le%20guin&email=scheduled_reader%40gmail.com")
+        .await;
+    Mock::given(path("/email"))
+        .and(method("POST"))
+        .respond_with(ResponseTemplate::new(200))
+        .expect(1..)
+        .mount(&test_app.email_server)
+        .await;
+    let id = create_draft(&test_app).await;
+    let mut issue = test_app.db.get_issue(&id).await.unwrap();
+    issue.status = IssueStatus::Scheduled;
+    issue.send_at = Some(Datetime(Utc::now() - Duration::seconds(1)));
+    test_app
+        .db
+        .update_issue(issue, IssueStatus::Draft)
+        .await
+        .unwrap();
+
+    enqueue_due_issues(&test_app.db).await.unwrap();
+    test_app.dispatch_all_pending_emails().await;
+
+    let issue = test_app.db.get_issue(&id).await.unwrap();
+    let saved = test_app
+        .db
+        .get_subscriber_by_email("scheduled_reader@gmail.com")
+        .await
+        .unwrap();
+    test_app.db.delete_subscriber(saved).await.unwrap();
+    assert_eq!(issue.status, IssueStatus::Sent);
+    assert!(issue.published_at.is_some());
+}
@@ -1,8 +1,26 @@
-use crate::helpers::spawn_app;
+use wiremock::{
+    matchers::{method, path},
+    Mock, ResponseTemplate,
+};
+use zero2prod::{configuration::Settings, subscriber::SubscriberStatus};
 
+use crate::helpers::{confirmation_token, spawn_app, spawn_app_with, TestApp};
+
+async fn mock_email(test_app: &TestApp, expected: u64) {
+    Mock::given(path("/email"))
+        .and(method("POST"))
+        .respond_with(ResponseTemplate::new(200))
+        .expect(expected)
+        .mount(&test_app.email_server)
+        .await;
+}
+fn without_cooldown(settings: &mut Settings) {
+    settings.subscriptions.resend_cooldown_minutes = 0;
+}
 #[tokio::test]
 async fn subscribe_returns_a_200_for_valid_form_data() {
     let test_app = spawn_app().await;
+    mock_email(&test_app, 1).await;
     let body = "name=le%20guin&email=ursula_le_guin%40gmail.com";
     let response = test_app.post_subscriptions(body).await;
     let saved = test_app
@@ -14,6 +32,158 @@ async fn subscribe_returns_a_200_for_valid_form_data() {
     assert_eq!(200, response.status().as_u16());
     assert_eq!(saved.email, "ursula_le_guin@gmail.com");
     assert_eq!(saved.username, "le guin");
+    assert_eq!(saved.status, SubscriberStatus::PendingConfirmation);
+}
+#[tokio::test]
+async fn the_confirmation_link_confirms_the_subscriber() {
+    let test_app = spawn_app().await;
+    mock_email(&test_app, 1).await;
+    test_app
+        .post_subscriptions("name=le%20guin&email=confirming_reader%40gmail.com")
+        .await;
+    let token = confirmation_token(&test_app.last_email().await);
+
+    let confirmed = test_app.get_confirmation(&token).await;
+    let reused = test_app.get_confirmation(&token).await;
+
+    let saved = test_app
+        .db
+        .get_subscriber_by_email("confirming_reader@gmail.com")
+        .await
+        .unwrap();
+    test_app.db.delete_subscriber(saved.clone()).await.unwrap();
+    assert_eq!(200, confirmed.status().as_u16());
+    assert_eq!(401, reused.status().as_u16());
+    assert_eq!(saved.status, SubscriberStatus::Confirmed);
+}
+#[tokio::test]
+async fn unknown_confirmation_tokens_are_rejected_with_a_401() {
+    let test_app = spawn_app().await;
+    let response = test_app.get_confirmation("not-a-real-token").await;
+    assert_eq!(401, response.status().as_u16());
+}
+#[tokio::test]
+async fn subscribing_again_while_pending_resends_a_fresh_link() {
+    let test_app = spawn_app_with(without_cooldown).await;
+    mock_email(&test_app, 2).await;
+    let body = "name=le%20guin&email=forgetful_reader%40gmail.com";
+    test_app.post_subscriptions(body).await;
+    let first = confirmation_token(&test_app.last_email().await);
+
+    let response = test_app.post_subscriptions(body).await;
+    let second = confirmation_token(&test_app.last_email().await);
+
+    let stale = test_app.get_confirmation(&first).await;
+    let fresh = test_app.get_confirmation(&second).await;
+    let saved = test_app
+        .db
+        .get_subscriber_by_email("forgetful_reader@gmail.com")
+        .await
+        .unwrap();
+    test_app.db.delete_subscriber(saved).await.unwrap();
+    assert_eq!(200, response.status().as_u16());
+    assert_ne!(first, second);
+    assert_eq!(401, stale.status().as_u16());
+    assert_eq!(200, fresh.status().as_u16());
+}
+#[tokio::test]
+async fn subscribing_answers_the_same_whether_or_not_the_email_is_known() {
+    let test_app = spawn_app_with(without_cooldown).await;
+    let body = "name=le%20guin&email=loyal_reader%40gmail.com";
+    test_app.create_confirmed_subscriber(body).await;
+    mock_email(&test_app, 3).await;
+
+    let new = test_app
+        .post_subscriptions("name=le%20guin&email=new_reader%40gmail.com")
+        .await;
+    let pending = test_app
+        .post_subscriptions("name=le%20guin&email=new_reader%40gmail.com")
+        .await;
+    let confirmed = test_app.post_subscriptions(body).await;
+    let notice = test_app.last_email().await;
+
+    for email in ["loyal_reader@gmail.com", "new_reader@gmail.com"] {
+        let saved = test_app.db.get_subscriber_by_email(email).await.unwrap();
+        test_app.db.delete_subscriber(saved).await.unwrap();
+    }
+    let mut answers = Vec::new();
+    for response in [new, pending, confirmed] {
+        assert_eq!(200, response.status().as_u16());
+        answers.push(response.text().await.unwrap());
+    }
+    assert!(answers.iter().all(|answer| *answer == answers[0]));
+    // Confirmed subscribers get a notice rather than another link.
+    assert_eq!(notice["To"], "loyal_reader@gmail.com");
+    assert!(!notice["TextBody"]
+        .as_str()
+        .unwrap()
+        .contains("subscription_token"));
+}
+#[tokio::test]
+async fn the_unsubscribe_link_asks_before_removing_the_subscriber() {
+    let test_app = spawn_app().await;
+    test_app
+        .create_confirmed_subscriber("name=le%20guin&email=leaving_reader%40gmail.com")
+        .await;
+    let subscriber = test_app
+        .db
+        .get_subscriber_by_email("leaving_reader@gmail.com")
+        .await
+        .unwrap();
+    let key = subscriber.id.id.to_raw();
+
+    let form = test_app.get_unsubscribe(&key).await;
+    let still_there = test_app.db.get_subscriber(&subscriber.id).await.unwrap();
+    let unsubscribed = test_app.post_unsubscribe(&key, &key).await;
+    let gone = test_app.db.get_subscriber(&subscriber.id).await.unwrap();
+
+    assert_eq!(200, form.status().as_u16());
+    assert!(form.text().await.unwrap().contains("method=\"post\""));
+    assert!(still_there.is_some());
+    assert_eq!(200, unsubscribed.status().as_u16());
+    assert!(gone.is_none());
+}
+#[tokio::test]
+async fn unsubscribing_with_another_subscribers_signature_is_rejected() {
+    let test_app = spawn_app().await;
+    test_app
+        .create_confirmed_subscriber("name=le%20guin&email=targeted_reader%40gmail.com")
+        .await;
+    let subscriber = test_app
+        .db
+        .get_subscriber_by_email("targeted_reader@gmail.com")
+        .await
+        .unwrap();
+
+    let response = test_app
+        .post_unsubscribe(&subscriber.id.id.to_raw(), "attacker")
+        .await;
+
+    let still_there = test_app.db.get_subscriber(&subscriber.id).await.unwrap();
+    test_app.db.delete_subscriber(subscriber).await.unwrap();
+    assert_eq!(401, response.status().as_u16());
+    assert!(still_there.is_some());
+}
+#[tokio::test]
+async fn repeated_subscriptions_within_the_cooldown_send_one_email() {
+    let test_app = spawn_app().await;
+    mock_email(&test_app, 1).await;
+    let body = "name=le%20guin&email=flooded_reader%40gmail.com";
+
+    let mut answers = Vec::new();
+    for _ in 0..3 {
+        let response = test_app.post_subscriptions(body).await;
+        assert_eq!(200, response.status().as_u16());
+        answers.push(response.text().await.unwrap());
+    }
+
+    let saved = test_app
+        .db
+        .get_subscriber_by_email("flooded_reader@gmail.com")
+        .await
+        .unwrap();
+    test_app.db.delete_subscriber(saved).await.unwrap();
+    assert!(answers.iter().all(|answer| *answer == answers[0]));
 }
 #[tokio::test]
 async fn subscribe_returns_a_400_when_data_is_missing() {